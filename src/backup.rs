@@ -0,0 +1,351 @@
+//! Timestamped snapshots of the files ghp rewrites, so that mutating commands can be undone.
+
+use crate::{remove_file, toml, write_file, GhpError, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of snapshots kept; older ones are pruned after each successful command.
+const RETENTION: usize = 20;
+const MANIFEST: &str = "manifest.toml";
+
+/// A file as it was when the snapshot was taken; `None` if it did not exist.
+struct SnapshotFile {
+    path: PathBuf,
+    contents: Option<Vec<u8>>,
+}
+
+pub struct Snapshot {
+    pub id: String,
+    pub command: String,
+    pub created: u64,
+    dir: PathBuf,
+    files: Vec<SnapshotFile>,
+}
+
+impl Snapshot {
+    /// Copies `paths` into a new snapshot directory.
+    pub fn create(paths: &[PathBuf], command: &str) -> Result<Self> {
+        let root = backups_dir()?;
+        fs::create_dir_all(&root)?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let base_id = format!("{}-{:03}", format_timestamp(now.as_secs(), true), now.subsec_millis());
+        let mut id = base_id.clone();
+        let mut attempt = 1;
+        let dir = loop {
+            let dir = root.join(&id);
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    attempt += 1;
+                    id = format!("{}-{}", base_id, attempt);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        let mut files = Vec::new();
+        let mut manifest_files = Vec::new();
+        for (index, path) in paths.iter().enumerate() {
+            let contents = match fs::read(path) {
+                Ok(contents) => Some(contents),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            let mut entry = toml::Table::new();
+            entry.insert("path", toml::Value::String(path.display().to_string()));
+            if let Some(contents) = &contents {
+                let stored = index.to_string();
+                fs::write(dir.join(&stored), contents)?;
+                entry.insert("stored", toml::Value::String(stored));
+            }
+            manifest_files.push(toml::Value::Table(entry));
+            files.push(SnapshotFile { path: path.clone(), contents });
+        }
+
+        let mut manifest = toml::Table::new();
+        manifest.insert("command", toml::Value::String(command.to_string()));
+        manifest.insert("created", toml::Value::Integer(now.as_secs() as i64));
+        manifest.insert("files", toml::Value::Array(manifest_files));
+        fs::write(dir.join(MANIFEST), toml::to_string(&manifest))?;
+
+        Ok(Self { id, command: command.to_string(), created: now.as_secs(), dir, files })
+    }
+
+    /// Keeps the snapshot if the command succeeded and changed something, then prunes old ones.
+    pub fn finish(self, succeeded: bool) -> Result<()> {
+        let changed = self.files.iter().any(|file| {
+            let current = fs::read(&file.path).ok();
+            current != file.contents
+        });
+        if !succeeded || !changed {
+            fs::remove_dir_all(&self.dir)?;
+            return Ok(());
+        }
+        prune(RETENTION)
+    }
+
+    /// Writes every file back as it was, deleting files that did not exist.
+    /// If any file fails, the ones already restored are put back, leaving every file as it was found.
+    pub fn restore(&self) -> Result<()> {
+        let mut current = Vec::new();
+        for file in &self.files {
+            current.push(match fs::read(&file.path) {
+                Ok(contents) => Some(contents),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            });
+        }
+
+        for (index, file) in self.files.iter().enumerate() {
+            if let Err(err) = put(&file.path, file.contents.as_deref()) {
+                for (restored, contents) in self.files[..index].iter().zip(&current) {
+                    // Best effort: the original error is the one worth reporting.
+                    let _ = put(&restored.path, contents.as_deref());
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|file| file.path.as_path())
+    }
+
+    pub fn delete(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    fn load(dir: &Path) -> Result<Self> {
+        let id = dir.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let invalid = |message: &str| GhpError::ConfigParse(format!("backup '{}': {}", id, message));
+
+        let manifest = toml::parse(&fs::read_to_string(dir.join(MANIFEST))?)
+            .map_err(|err| invalid(&err.to_string()))?;
+        let command = match manifest.get("command") {
+            Some(toml::Value::String(command)) => command.clone(),
+            _ => return Err(invalid("missing 'command'")),
+        };
+        let created = match manifest.get("created") {
+            Some(toml::Value::Integer(created)) => *created as u64,
+            _ => return Err(invalid("missing 'created'")),
+        };
+        let Some(toml::Value::Array(entries)) = manifest.get("files") else {
+            return Err(invalid("missing 'files'"));
+        };
+
+        let mut files = Vec::new();
+        for entry in entries {
+            let toml::Value::Table(entry) = entry else {
+                return Err(invalid("'files' must be an array of tables"));
+            };
+            let Some(toml::Value::String(path)) = entry.get("path") else {
+                return Err(invalid("file entry is missing 'path'"));
+            };
+            let contents = match entry.get("stored") {
+                Some(toml::Value::String(stored)) => Some(fs::read(dir.join(stored))?),
+                None => None,
+                Some(_) => return Err(invalid("'stored' must be a string")),
+            };
+            files.push(SnapshotFile { path: PathBuf::from(path), contents });
+        }
+
+        Ok(Self { id, command, created, dir: dir.to_path_buf(), files })
+    }
+}
+
+fn put(path: &Path, contents: Option<&[u8]>) -> Result<()> {
+    match contents {
+        Some(contents) => write_file(path, contents),
+        None => remove_file(path),
+    }
+}
+
+fn backups_dir() -> Result<PathBuf> {
+    let state_home = match std::env::var_os("XDG_STATE_HOME").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path,
+        _ => crate::home_dir()?.join(".local").join("state"),
+    };
+    Ok(state_home.join("ghp").join("backups"))
+}
+
+/// All snapshots, oldest first.
+pub fn list() -> Result<Vec<Snapshot>> {
+    let root = backups_dir()?;
+    let mut dirs: Vec<PathBuf> = match fs::read_dir(&root) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.join(MANIFEST).is_file())
+            .collect(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    dirs.sort();
+    dirs.iter().map(|dir| Snapshot::load(dir)).collect()
+}
+
+/// The snapshot with the given id, or the most recent one.
+pub fn find(id: Option<&str>) -> Result<Snapshot> {
+    let root = backups_dir()?;
+    match id {
+        Some(id) => {
+            let dir = root.join(id);
+            if id.contains(['/', '\\']) || !dir.join(MANIFEST).is_file() {
+                return Err(GhpError::MissingConfig(format!("no backup with id '{}'", id)));
+            }
+            Snapshot::load(&dir)
+        }
+        None => list()?
+            .pop()
+            .ok_or_else(|| GhpError::MissingConfig("there are no backups to restore".to_string())),
+    }
+}
+
+fn prune(keep: usize) -> Result<()> {
+    let snapshots = list()?;
+    let excess = snapshots.len().saturating_sub(keep);
+    for snapshot in snapshots.into_iter().take(excess) {
+        snapshot.delete()?;
+    }
+    Ok(())
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time.
+pub fn format_timestamp(secs: u64, compact: bool) -> String {
+    let days = (secs / 86_400) as i64;
+    let seconds_of_day = secs % 86_400;
+    let (hour, minute, second) = (seconds_of_day / 3600, seconds_of_day % 3600 / 60, seconds_of_day % 60);
+
+    // Civil-from-days conversion (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    if compact {
+        format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, hour, minute, second)
+    } else {
+        format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, hour, minute, second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// `XDG_STATE_HOME` is process-wide, so tests that point it somewhere take turns.
+    static STATE_HOME: Mutex<()> = Mutex::new(());
+
+    /// Runs `test` with `XDG_STATE_HOME` set to a fresh directory, passing it a scratch directory for files.
+    fn with_state_home(name: &str, test: impl FnOnce(&Path)) {
+        let _guard = STATE_HOME.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let root = std::env::temp_dir().join(format!("ghp-backup-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("files")).unwrap();
+        std::env::set_var("XDG_STATE_HOME", root.join("state"));
+        test(&root.join("files"));
+        std::env::remove_var("XDG_STATE_HOME");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn snapshots_restore_files() {
+        with_state_home("restore", |files| {
+            let existing = files.join("existing");
+            let created = files.join("created");
+            fs::write(&existing, "before").unwrap();
+
+            let snapshot = Snapshot::create(&[existing.clone(), created.clone()], "ghp test").unwrap();
+            fs::write(&existing, "after").unwrap();
+            fs::write(&created, "new").unwrap();
+            snapshot.finish(true).unwrap();
+
+            let snapshot = find(None).unwrap();
+            assert_eq!(snapshot.command, "ghp test");
+            assert_eq!(snapshot.paths().collect::<Vec<_>>(), vec![existing.as_path(), created.as_path()]);
+            assert_eq!(find(Some(&snapshot.id)).unwrap().id, snapshot.id);
+
+            snapshot.restore().unwrap();
+            assert_eq!(fs::read_to_string(&existing).unwrap(), "before");
+            assert!(!created.exists());
+        });
+    }
+
+    #[test]
+    fn unchanged_or_failed_commands_keep_no_snapshot() {
+        with_state_home("finish", |files| {
+            let path = files.join("file");
+            fs::write(&path, "same").unwrap();
+            Snapshot::create(std::slice::from_ref(&path), "ghp unchanged").unwrap().finish(true).unwrap();
+
+            let snapshot = Snapshot::create(std::slice::from_ref(&path), "ghp failed").unwrap();
+            fs::write(&path, "changed").unwrap();
+            snapshot.finish(false).unwrap();
+
+            assert!(list().unwrap().is_empty());
+            assert!(find(None).is_err());
+        });
+    }
+
+    #[test]
+    fn failed_restore_rolls_back() {
+        with_state_home("rollback", |files| {
+            let first = files.join("first");
+            let second = files.join("second");
+            fs::write(&first, "old").unwrap();
+            let snapshot = Snapshot::create(&[first.clone(), second.clone()], "ghp test").unwrap();
+
+            // `second` did not exist, but a directory that cannot be removed as a file now does.
+            fs::write(&first, "current").unwrap();
+            fs::create_dir(&second).unwrap();
+            assert!(snapshot.restore().is_err());
+            assert_eq!(fs::read_to_string(&first).unwrap(), "current");
+            assert!(second.is_dir());
+        });
+    }
+
+    #[test]
+    fn prunes_oldest_snapshots() {
+        with_state_home("prune", |files| {
+            let path = files.join("file");
+            let mut ids = Vec::new();
+            for index in 0..5 {
+                let snapshot = Snapshot::create(std::slice::from_ref(&path), &format!("ghp {}", index)).unwrap();
+                ids.push(snapshot.id.clone());
+                fs::write(&path, index.to_string()).unwrap();
+                snapshot.finish(true).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+            prune(3).unwrap();
+            let kept: Vec<String> = list().unwrap().into_iter().map(|snapshot| snapshot.id).collect();
+            assert_eq!(kept, ids[2..]);
+        });
+    }
+
+    #[test]
+    fn rejects_unknown_ids() {
+        with_state_home("find", |_| {
+            assert!(find(Some("20000101-000000-000")).is_err());
+            assert!(find(Some("../state")).is_err());
+        });
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_timestamp(0, false), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951_827_696, true), "20000229-123456");
+    }
+}
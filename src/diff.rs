@@ -0,0 +1,193 @@
+//! Line-based unified diffs, used to preview changes with `--dry-run`.
+
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Renders the changes from `old` to `new` as a unified diff, or an empty string if they are equal.
+pub fn unified(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let ops = diff_lines(&old_lines, &new_lines);
+    if ops.iter().all(|op| matches!(op, Op::Equal(..))) {
+        return String::new();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_label, new_label);
+    for (start, end) in hunks(&ops) {
+        let hunk = &ops[start..end];
+        let old_start = hunk.iter().find_map(|op| match op {
+            Op::Equal(i, _) | Op::Delete(i) => Some(*i),
+            Op::Insert(_) => None,
+        });
+        let new_start = hunk.iter().find_map(|op| match op {
+            Op::Equal(_, j) | Op::Insert(j) => Some(*j),
+            Op::Delete(_) => None,
+        });
+        let old_count = hunk.iter().filter(|op| !matches!(op, Op::Insert(_))).count();
+        let new_count = hunk.iter().filter(|op| !matches!(op, Op::Delete(_))).count();
+
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_count, &ops[..start], true),
+            range(new_start, new_count, &ops[..start], false),
+        ));
+        for op in hunk {
+            let (prefix, line) = match *op {
+                Op::Equal(i, _) => (' ', old_lines[i]),
+                Op::Delete(i) => ('-', old_lines[i]),
+                Op::Insert(j) => ('+', new_lines[j]),
+            };
+            out.push(prefix);
+            out.push_str(line);
+            if !line.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    out
+}
+
+/// Formats a hunk range; empty ranges point at the line before the change.
+fn range(start: Option<usize>, count: usize, before: &[Op], old: bool) -> String {
+    let start = match start {
+        Some(index) => index + 1,
+        None => before.iter()
+            .filter(|op| if old { !matches!(op, Op::Insert(_)) } else { !matches!(op, Op::Delete(_)) })
+            .count(),
+    };
+    format!("{},{}", start, count)
+}
+
+/// Groups changed operations into hunks with surrounding context, merging nearby ones.
+fn hunks(ops: &[Op]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (index, op) in ops.iter().enumerate() {
+        if matches!(op, Op::Equal(..)) {
+            continue;
+        }
+        let start = index.saturating_sub(CONTEXT);
+        let end = (index + 1 + CONTEXT).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+/// Computes a minimal edit script using the longest common subsequence.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Op> {
+    let (n, m) = (old.len(), new.len());
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[at(i, j)] = if old[i] == new[j] {
+                lcs[at(i + 1, j + 1)] + 1
+            } else {
+                lcs[at(i + 1, j)].max(lcs[at(i, j + 1)])
+            };
+        }
+    }
+
+    let mut ops = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            ops.push(Op::Equal(i, j));
+            i += 1;
+            j += 1;
+        } else if lcs[at(i + 1, j)] >= lcs[at(i, j + 1)] {
+            ops.push(Op::Delete(i));
+            i += 1;
+        } else {
+            ops.push(Op::Insert(j));
+            j += 1;
+        }
+    }
+    ops.extend((i..n).map(Op::Delete));
+    ops.extend((j..m).map(Op::Insert));
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(range: std::ops::RangeInclusive<usize>) -> String {
+        range.map(|n| format!("{}\n", n)).collect()
+    }
+
+    /// `lines(range)` with the given lines replaced, `None` deleting them.
+    fn edit(range: std::ops::RangeInclusive<usize>, edits: &[(usize, Option<&str>)]) -> String {
+        range.filter_map(|n| match edits.iter().find(|(line, _)| *line == n) {
+            Some((_, replacement)) => replacement.map(|text| format!("{}\n", text)),
+            None => Some(format!("{}\n", n)),
+        }).collect()
+    }
+
+    fn diff(old: &str, new: &str) -> String {
+        unified(old, new, "a", "b")
+    }
+
+    #[test]
+    fn equal_inputs_have_no_diff() {
+        assert_eq!(diff("", ""), "");
+        assert_eq!(diff("a\nb\n", "a\nb\n"), "");
+    }
+
+    #[test]
+    fn insert_only() {
+        let new = edit(1..=10, &[(5, Some("5\nnew"))]);
+        assert_eq!(diff(&lines(1..=10), &new), "--- a\n+++ b\n@@ -3,6 +3,7 @@\n 3\n 4\n 5\n+new\n 6\n 7\n 8\n");
+        assert_eq!(diff("", "a\nb\n"), "--- a\n+++ b\n@@ -0,0 +1,2 @@\n+a\n+b\n");
+        assert_eq!(diff("a\n", "a\nb\n"), "--- a\n+++ b\n@@ -1,1 +1,2 @@\n a\n+b\n");
+    }
+
+    #[test]
+    fn delete_only() {
+        let old = lines(1..=10);
+        let new = edit(1..=10, &[(5, None)]);
+        assert_eq!(diff(&old, &new), "--- a\n+++ b\n@@ -2,7 +2,6 @@\n 2\n 3\n 4\n-5\n 6\n 7\n 8\n");
+        assert_eq!(diff("a\nb\n", ""), "--- a\n+++ b\n@@ -1,2 +0,0 @@\n-a\n-b\n");
+        assert_eq!(diff("a\nb\n", "b\n"), "--- a\n+++ b\n@@ -1,2 +1,1 @@\n-a\n b\n");
+    }
+
+    #[test]
+    fn missing_final_newline() {
+        assert_eq!(
+            diff("a\nb", "a\nb\n"),
+            "--- a\n+++ b\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+b\n"
+        );
+        assert_eq!(
+            diff("a\n", "a\nb"),
+            "--- a\n+++ b\n@@ -1,1 +1,2 @@\n a\n+b\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn nearby_changes_share_a_hunk() {
+        let old = lines(1..=20);
+        let new = edit(1..=20, &[(5, Some("five")), (11, Some("eleven"))]);
+        assert_eq!(
+            diff(&old, &new),
+            "--- a\n+++ b\n@@ -2,13 +2,13 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n 9\n 10\n-11\n+eleven\n 12\n 13\n 14\n"
+        );
+    }
+
+    #[test]
+    fn distant_changes_get_separate_hunks() {
+        let old = lines(1..=20);
+        let new = edit(1..=20, &[(2, Some("two")), (15, None), (20, Some("20\n21"))]);
+        assert_eq!(
+            diff(&old, &new),
+            "--- a\n+++ b\n@@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n@@ -12,9 +12,9 @@\n 12\n 13\n 14\n-15\n 16\n 17\n 18\n 19\n 20\n+21\n"
+        );
+    }
+}
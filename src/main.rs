@@ -1,8 +1,17 @@
+mod backup;
+mod diff;
+mod json;
+mod ssh_config;
+mod toml;
+
 use clap::{Arg, ArgMatches, Command};
-use std::collections::HashMap;
-use std::fs::{self, OpenOptions};
-use std::io::{self, Read, Write};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use ssh_config::SshConfig;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -13,123 +22,998 @@ pub enum GhpError {
     ProfileNotFound(String),
     #[error("Failed to parse config: {0}")]
     ConfigParse(String),
+    #[error("Invalid config:\n{}", format_diagnostics(.0))]
+    InvalidConfig(Vec<Diagnostic>),
+    #[error("Invalid profile name '{0}': only letters, digits, '.', '_' and '-' are allowed")]
+    InvalidProfileName(String),
+    #[error("Invalid username {0:?}: it must not contain newlines, '=' or '['")]
+    InvalidUsername(String),
+    #[error("Invalid email '{0}'")]
+    InvalidEmail(String),
+    #[error("Invalid host '{0}': expected a hostname such as gitlab.com")]
+    InvalidHost(String),
+    #[error("Invalid SSH user {0:?}: it must not be empty or contain whitespace")]
+    InvalidSshUser(String),
+    #[error("Host '{0}' is bound more than once in the same profile")]
+    DuplicateHost(String),
+    #[error("Invalid SSH option: {0}")]
+    InvalidSshOption(String),
+    #[error("Could not run '{0}': {1}")]
+    CommandFailed(String, io::Error),
+    #[error("git clone of '{0}' failed")]
+    CloneFailed(String),
+    #[error("Invalid signing key {0:?}: it must not be empty or span several lines")]
+    InvalidSigningKey(String),
+    #[error("Invalid signing format {0:?}: expected openpgp, x509 or ssh")]
+    InvalidSigningFormat(String),
+    #[error("SSH key '{0}' does not exist")]
+    SshKeyNotFound(PathBuf),
+    #[error("SSH key '{0}' is a public key; use the private key instead")]
+    SshKeyIsPublic(PathBuf),
+    #[error("SSH key '{0}' has unsafe permissions {1:o}; run `chmod 600` on it")]
+    UnsafeKeyPermissions(PathBuf, u32),
+    #[error("Environment variable '{0}' is not set")]
+    UndefinedVariable(String),
+    #[error("Profile '{0}' already exists (use --force to overwrite)")]
+    ProfileExists(String),
     #[error("Missing configuration: {0}")]
     MissingConfig(String),
+    #[error("Invalid JSON input: {0}")]
+    InvalidJson(String),
+    #[error("SSH and git identities disagree: {0}")]
+    IdentityMismatch(String),
 }
 
 type Result<T> = std::result::Result<T, GhpError>;
 
+/// A problem found in the GHP config, pointing at the offending line.
 #[derive(Debug)]
+pub struct Diagnostic {
+    path: PathBuf,
+    line: usize,
+    message: String,
+    help: Option<String>,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.path.display(), self.line, self.message)?;
+        if let Some(help) = &self.help {
+            write!(f, "\n    help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+fn format_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics.iter()
+        .map(|diagnostic| format!("  {}", diagnostic))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Collects every problem in a config file so they can be reported together.
+struct Diagnostics {
+    path: PathBuf,
+    items: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), items: Vec::new() }
+    }
+
+    fn error(&mut self, line: usize, message: impl Into<String>) {
+        self.push(line, message.into(), None);
+    }
+
+    fn push(&mut self, line: usize, message: String, help: Option<String>) {
+        self.items.push(Diagnostic { path: self.path.clone(), line, message, help });
+    }
+
+    fn unknown_key(&mut self, line: usize, key: &str, context: &str, known: &[&str]) {
+        let help = match did_you_mean(key, known) {
+            Some(candidate) => format!("did you mean '{}'?", candidate),
+            None => format!("expected one of: {}", known.join(", ")),
+        };
+        self.push(line, format!("unknown key '{}' {}", key, context), Some(help));
+    }
+
+    fn string(&mut self, entry: &toml::Entry) -> Option<String> {
+        match &entry.value {
+            toml::Value::String(value) => Some(value.clone()),
+            other => {
+                self.error(entry.line, format!("'{}' must be a string, found {}", entry.key, other.type_name()));
+                None
+            }
+        }
+    }
+
+    fn port(&mut self, entry: &toml::Entry, profile_name: &str) -> Option<u16> {
+        match &entry.value {
+            toml::Value::Integer(port) if (1..=65535).contains(port) => Some(*port as u16),
+            toml::Value::Integer(port) => {
+                self.error(
+                    entry.line,
+                    format!("'{}' of profile '{}' must be between 1 and 65535, found {}", entry.key, profile_name, port),
+                );
+                None
+            }
+            other => {
+                self.error(entry.line, format!("'{}' must be an integer, found {}", entry.key, other.type_name()));
+                None
+            }
+        }
+    }
+
+    /// Reads an `ssh_options` table; booleans become `yes`/`no`.
+    fn ssh_options(&mut self, entry: &toml::Entry, profile_name: &str) -> Vec<(String, String)> {
+        let toml::Value::Table(fields) = &entry.value else {
+            self.error(entry.line, format!("'ssh_options' must be a table, found {}", entry.value.type_name()));
+            return Vec::new();
+        };
+        let mut options: Vec<(String, String)> = Vec::new();
+        for field in fields.entries() {
+            let value = match &field.value {
+                toml::Value::String(value) => value.clone(),
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Boolean(value) => if *value { "yes" } else { "no" }.to_string(),
+                other => {
+                    self.error(field.line, format!("SSH option '{}' must be a string, found {}", field.key, other.type_name()));
+                    continue;
+                }
+            };
+            if options.iter().any(|(keyword, _)| keyword.eq_ignore_ascii_case(&field.key)) {
+                self.error(field.line, format!("SSH option '{}' is set more than once", field.key));
+            } else if let Err(err) = validate_ssh_option(&field.key, &value) {
+                self.error(field.line, format!("{} (in profile '{}')", err, profile_name));
+            } else {
+                options.push((field.key.clone(), value));
+            }
+        }
+        options
+    }
+
+    /// Validates a single profile field, optionally checking that the SSH key is usable.
+    fn check_field(&mut self, line: usize, profile_name: &str, key: &str, value: &str, check_keys: bool) {
+        if value.is_empty() {
+            self.error(line, format!("'{}' of profile '{}' is empty", key, profile_name));
+            return;
+        }
+        let result = match key {
+            "username" => validate_username(value),
+            "email" => validate_email(value),
+            "host" => validate_host(value),
+            "ssh_user" => validate_ssh_user(value),
+            "signing_key" => validate_signing_key(value),
+            "signing_format" => validate_signing_format(value),
+            "ssh_key" if check_keys => expand_path(Path::new(value))
+                .and_then(|ssh_key| validate_ssh_key(&ssh_key)),
+            _ => Ok(()),
+        };
+        if let Err(err) = result {
+            self.error(line, format!("{} (in profile '{}')", err, profile_name));
+        }
+    }
+
+    fn finish<T>(mut self, value: T) -> Result<T> {
+        if self.items.is_empty() {
+            Ok(value)
+        } else {
+            self.items.sort_by_key(|diagnostic| diagnostic.line);
+            Err(GhpError::InvalidConfig(self.items))
+        }
+    }
+}
+
+/// Suggests the closest known key for a likely typo.
+fn did_you_mean<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known.iter()
+        .map(|candidate| (edit_distance(key, candidate), *candidate))
+        .filter(|(distance, candidate)| *distance <= 2.max(candidate.len() / 3))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[derive(Debug, Clone)]
 struct Profile {
     username: String,
     email: String,
     ssh_key: PathBuf,
+    /// SSH host of the forge: `github.com`, `gitlab.com`, `bitbucket.org` or a GitHub Enterprise server.
+    host: String,
+    ssh_port: Option<u16>,
+    ssh_user: Option<String>,
+    /// Extra directives for the generated `Host` blocks, e.g. `ProxyJump`, in the order given.
+    ssh_options: Vec<(String, String)>,
+    /// Git `user.signingkey`; commits and tags are signed when set.
+    signing_key: Option<String>,
+    /// Git `gpg.format`: `openpgp`, `x509` or `ssh`.
+    signing_format: Option<String>,
+    /// Further forges this profile reaches with their own keys, e.g. a self-hosted GitLab.
+    hosts: Vec<HostBinding>,
+}
+
+/// A forge host and the key, port and user a profile connects to it with.
+#[derive(Debug, Clone)]
+struct HostBinding {
+    host: String,
+    ssh_key: PathBuf,
+    ssh_port: Option<u16>,
+    ssh_user: Option<String>,
+    ssh_options: Vec<(String, String)>,
+}
+
+impl HostBinding {
+    /// The SSH key with `~` and environment variables expanded.
+    fn ssh_key_path(&self) -> Result<PathBuf> {
+        expand_path(&self.ssh_key)
+    }
+
+    fn ssh_user(&self) -> &str {
+        self.ssh_user.as_deref().unwrap_or(DEFAULT_SSH_USER)
+    }
+
+    /// The `Host <forge>-<profile>` alias used to reach the forge with this binding's key.
+    fn alias(&self, profile_name: &str) -> String {
+        alias_host(&self.host, profile_name)
+    }
+}
+
+const DEFAULT_HOST: &str = "github.com";
+const DEFAULT_SSH_USER: &str = "git";
+/// What git signs with when `gpg.format` is not set.
+const DEFAULT_SIGNING_FORMAT: &str = "openpgp";
+
+impl Profile {
+    fn new(username: String, email: String, ssh_key: PathBuf) -> Self {
+        Self {
+            username,
+            email,
+            ssh_key,
+            host: DEFAULT_HOST.to_string(),
+            ssh_port: None,
+            ssh_user: None,
+            ssh_options: Vec::new(),
+            signing_key: None,
+            signing_format: None,
+            hosts: Vec::new(),
+        }
+    }
+
+    /// Every host the profile connects to, starting with its primary one.
+    fn bindings(&self) -> Vec<HostBinding> {
+        let primary = HostBinding {
+            host: self.host.clone(),
+            ssh_key: self.ssh_key.clone(),
+            ssh_port: self.ssh_port,
+            ssh_user: self.ssh_user.clone(),
+            ssh_options: self.ssh_options.clone(),
+        };
+        std::iter::once(primary).chain(self.hosts.iter().cloned()).collect()
+    }
+
+    fn ssh_user(&self) -> &str {
+        self.ssh_user.as_deref().unwrap_or(DEFAULT_SSH_USER)
+    }
 }
 
 struct Config {
     ssh_config_path: PathBuf,
     ghp_config_path: PathBuf,
-    profiles: HashMap<String, Profile>,
+    profiles: BTreeMap<String, Profile>,
 }
 
+const CONFIG_VERSION: i64 = 2;
+const CONFIG_KEYS: &[&str] = &["version", "ssh_config", "ghp_config", "profiles"];
+const PROFILE_KEYS: &[&str] = &[
+    "username", "email", "ssh_key", "host", "ssh_port", "ssh_user", "ssh_options", "signing_key", "signing_format", "hosts",
+];
+const HOST_BINDING_KEYS: &[&str] = &["host", "ssh_key", "ssh_port", "ssh_user", "ssh_options"];
+const LEGACY_CONFIG_KEYS: &[&str] = &["ssh_config", "ghp_config"];
+const LEGACY_PROFILE_KEYS: &[&str] = &["username", "email", "ssh_key"];
+
 impl Config {
-    fn load() -> Result<Self> {
-        let default_paths = get_default_paths()?;
-        let config_content = fs::read_to_string(&default_paths.1)
-            .unwrap_or_else(|_| format!("ssh_config={}\nghp_config={}", 
-                default_paths.0.display(), 
-                default_paths.1.display()));
-
-        let mut config = Self::parse_config(&config_content)?;
-        config.ssh_config_path = default_paths.0;
-        config.ghp_config_path = default_paths.1;
+    /// Loads the config at `path`, following the `ghp_config` redirect left by `ghp setup -g`.
+    fn load(path: &Path) -> Result<Self> {
+        let path = Self::resolve(path)?;
+        let mut config = Self::read(&path)?;
+        config.ghp_config_path = path;
+        config.ssh_config_path = expand_path(&config.ssh_config_path)?;
         Ok(config)
     }
 
-    fn parse_config(content: &str) -> Result<Self> {
-        let mut profiles = HashMap::new();
-        let mut ssh_config_path = None;
-        let mut ghp_config_path = None;
-        let mut current_profile = None;
+    /// A config without profiles whose `ghp_config` points elsewhere is a redirect to that file.
+    /// Only reads `path`: a legacy file is parsed as is and left for `migrate`.
+    fn resolve(path: &Path) -> Result<PathBuf> {
+        let config = Self::read(path)?;
+        Ok(config.redirect_target(path)?.unwrap_or_else(|| path.to_path_buf()))
+    }
+
+    fn redirect_target(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let target = expand_path(&self.ghp_config_path)?;
+        Ok((self.profiles.is_empty() && target != path).then_some(target))
+    }
+
+    /// Parses `path` without migrating it; a missing file is an empty config.
+    fn read(path: &Path) -> Result<Self> {
+        match read_file(path) {
+            Ok(content) if Self::is_legacy(&content) => Self::parse_legacy_config(&content, path, false),
+            Ok(content) => Self::parse_config(&content, path, false),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self {
+                ssh_config_path: get_default_paths()?.0,
+                ghp_config_path: path.to_path_buf(),
+                profiles: BTreeMap::new(),
+            }),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Files written before the TOML format have no `version` key.
+    fn is_legacy(content: &str) -> bool {
+        !content.trim().is_empty() && !content.lines().any(|line| {
+            line.trim_start()
+                .strip_prefix("version")
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        })
+    }
+
+    /// Converts a legacy config to the current format, keeping a backup of the original.
+    /// Does nothing for current configs. Only `run_mutating` calls this, while holding `ConfigLock`.
+    fn migrate(path: &Path) -> Result<()> {
+        let content = match read_file(path) {
+            Ok(content) if Self::is_legacy(&content) => content,
+            Ok(_) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let config = Self::parse_legacy_config(&content, path, false)?;
+
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".v1.bak");
+        let backup = PathBuf::from(backup);
+        if !dry_run() {
+            fs::copy(path, &backup)?;
+        }
+        config.save_to(path)?;
+
+        if !dry_run() {
+            eprintln!(
+                "Migrated {} to config version {} (backup saved to {})",
+                path.display(),
+                CONFIG_VERSION,
+                backup.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn parse_config(content: &str, path: &Path, check_keys: bool) -> Result<Self> {
+        let mut diagnostics = Diagnostics::new(path);
+        let table = match toml::parse(content) {
+            Ok(table) => table,
+            Err(err) => {
+                diagnostics.error(err.line, err.message);
+                return Err(GhpError::InvalidConfig(diagnostics.items));
+            }
+        };
+
+        let mut ssh_config_path = get_default_paths()?.0;
+        let mut ghp_config_path = path.to_path_buf();
+        let mut profiles = BTreeMap::new();
+        let mut has_version = false;
+
+        for entry in table.entries() {
+            match entry.key.as_str() {
+                "version" => {
+                    has_version = true;
+                    match &entry.value {
+                        toml::Value::Integer(CONFIG_VERSION) => {}
+                        toml::Value::Integer(version) => diagnostics.push(
+                            entry.line,
+                            format!("unsupported config version {}", version),
+                            Some(format!("this ghp supports version {}; try upgrading ghp", CONFIG_VERSION)),
+                        ),
+                        other => diagnostics.error(entry.line, format!("'version' must be an integer, found {}", other.type_name())),
+                    }
+                }
+                "ssh_config" => {
+                    if let Some(value) = diagnostics.string(entry) {
+                        ssh_config_path = PathBuf::from(value);
+                    }
+                }
+                "ghp_config" => {
+                    if let Some(value) = diagnostics.string(entry) {
+                        ghp_config_path = PathBuf::from(value);
+                    }
+                }
+                "profiles" => match &entry.value {
+                    toml::Value::Table(entries) => {
+                        for profile_entry in entries.entries() {
+                            if let Some(profile) = Self::parse_profile(profile_entry, &mut diagnostics, check_keys) {
+                                profiles.insert(profile_entry.key.clone(), profile);
+                            }
+                        }
+                    }
+                    other => diagnostics.error(entry.line, format!("'profiles' must be a table, found {}", other.type_name())),
+                },
+                key => diagnostics.unknown_key(entry.line, key, "at the top level", CONFIG_KEYS),
+            }
+        }
+        if !has_version {
+            diagnostics.push(1, "missing 'version'".to_string(), Some(format!("add `version = {}` at the top of the file", CONFIG_VERSION)));
+        }
+
+        diagnostics.finish(Self {
+            ssh_config_path,
+            ghp_config_path,
+            profiles,
+        })
+    }
+
+    fn parse_profile(entry: &toml::Entry, diagnostics: &mut Diagnostics, check_keys: bool) -> Option<Profile> {
+        let name = &entry.key;
+        if validate_profile_name(name).is_err() {
+            diagnostics.error(entry.line, format!("invalid profile name '{}'", name));
+        }
+        let toml::Value::Table(fields) = &entry.value else {
+            diagnostics.error(entry.line, format!("profile '{}' must be a table, found {}", name, entry.value.type_name()));
+            return None;
+        };
+
+        let mut username = None;
+        let mut email = None;
+        let mut ssh_key = None;
+        let mut host = None;
+        let mut ssh_port = None;
+        let mut ssh_user = None;
+        let mut ssh_options = Vec::new();
+        let mut signing_key = None;
+        let mut signing_format = None;
+        let mut hosts = Vec::new();
+        for field in fields.entries() {
+            match field.key.as_str() {
+                "ssh_port" => {
+                    ssh_port = diagnostics.port(field, name);
+                    continue;
+                }
+                "ssh_options" => {
+                    ssh_options = diagnostics.ssh_options(field, name);
+                    continue;
+                }
+                "hosts" => {
+                    hosts = Self::parse_host_bindings(field, name, diagnostics, check_keys);
+                    continue;
+                }
+                _ => {}
+            }
+            let slot = match field.key.as_str() {
+                "username" => &mut username,
+                "email" => &mut email,
+                "ssh_key" => &mut ssh_key,
+                "host" => &mut host,
+                "ssh_user" => &mut ssh_user,
+                "signing_key" => &mut signing_key,
+                "signing_format" => &mut signing_format,
+                key => {
+                    diagnostics.unknown_key(field.line, key, &format!("in profile '{}'", name), PROFILE_KEYS);
+                    continue;
+                }
+            };
+            if let Some(value) = diagnostics.string(field) {
+                diagnostics.check_field(field.line, name, &field.key, &value, check_keys);
+                *slot = Some(value);
+            }
+        }
+
+        let mut require = |value: Option<String>, key: &str| {
+            if value.is_none() {
+                diagnostics.error(entry.line, format!("profile '{}' is missing '{}'", name, key));
+            }
+            value.unwrap_or_default()
+        };
+        Some(Profile {
+            username: require(username, "username"),
+            email: require(email, "email"),
+            ssh_key: PathBuf::from(require(ssh_key, "ssh_key")),
+            host: host.unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            ssh_port,
+            ssh_user,
+            ssh_options,
+            signing_key,
+            signing_format,
+            hosts,
+        })
+    }
+
+    /// Reads the `[[profiles.<name>.hosts]]` array of additional host bindings.
+    fn parse_host_bindings(entry: &toml::Entry, name: &str, diagnostics: &mut Diagnostics, check_keys: bool) -> Vec<HostBinding> {
+        let toml::Value::Array(items) = &entry.value else {
+            diagnostics.error(entry.line, format!("'hosts' must be an array of tables, found {}", entry.value.type_name()));
+            return Vec::new();
+        };
+        let mut bindings = Vec::new();
+        for item in items {
+            let toml::Value::Table(fields) = item else {
+                diagnostics.error(entry.line, format!("'hosts' must be an array of tables, found {}", item.type_name()));
+                continue;
+            };
+            let line = fields.entries().next().map_or(entry.line, |field| field.line);
+            let mut host = None;
+            let mut ssh_key = None;
+            let mut ssh_port = None;
+            let mut ssh_user = None;
+            let mut ssh_options = Vec::new();
+            for field in fields.entries() {
+                let slot = match field.key.as_str() {
+                    "ssh_port" => {
+                        ssh_port = diagnostics.port(field, name);
+                        continue;
+                    }
+                    "ssh_options" => {
+                        ssh_options = diagnostics.ssh_options(field, name);
+                        continue;
+                    }
+                    "host" => &mut host,
+                    "ssh_key" => &mut ssh_key,
+                    "ssh_user" => &mut ssh_user,
+                    key => {
+                        diagnostics.unknown_key(field.line, key, &format!("in a host of profile '{}'", name), HOST_BINDING_KEYS);
+                        continue;
+                    }
+                };
+                if let Some(value) = diagnostics.string(field) {
+                    diagnostics.check_field(field.line, name, &field.key, &value, check_keys);
+                    *slot = Some(value);
+                }
+            }
+            let (Some(host), Some(ssh_key)) = (host, ssh_key) else {
+                diagnostics.error(line, format!("each host of profile '{}' needs 'host' and 'ssh_key'", name));
+                continue;
+            };
+            bindings.push(HostBinding { host, ssh_key: PathBuf::from(ssh_key), ssh_port, ssh_user, ssh_options });
+        }
+        bindings
+    }
+
+    /// Reads the original INI-like format, kept only to migrate old files.
+    fn parse_legacy_config(content: &str, path: &Path, check_keys: bool) -> Result<Self> {
+        let mut diagnostics = Diagnostics::new(path);
+        let mut ssh_config_path = get_default_paths()?.0;
+        let mut ghp_config_path = path.to_path_buf();
+        // Field name -> (line, value)
+        type Fields = BTreeMap<String, (usize, String)>;
+        // Profile name -> (header line, fields)
+        let mut sections: BTreeMap<String, (usize, Fields)> = BTreeMap::new();
+        let mut current_profile: Option<String> = None;
 
-        for line in content.lines() {
+        for (index, line) in content.lines().enumerate() {
+            let line_number = index + 1;
             let line = line.trim();
             if line.is_empty() || line.starts_with('#') {
                 continue;
             }
 
             if line.starts_with('[') && line.ends_with(']') {
-                current_profile = Some(line[1..line.len() - 1].to_string());
+                let name = line[1..line.len() - 1].to_string();
+                if validate_profile_name(&name).is_err() {
+                    diagnostics.error(line_number, format!("invalid profile name '{}'", name));
+                }
+                if sections.contains_key(&name) {
+                    diagnostics.error(line_number, format!("profile '{}' is defined more than once", name));
+                }
+                sections.entry(name.clone()).or_insert_with(|| (line_number, BTreeMap::new()));
+                current_profile = Some(name);
                 continue;
             }
 
-            let parts: Vec<&str> = line.splitn(2, '=').collect();
-            if parts.len() != 2 {
+            let Some((key, value)) = line.split_once('=') else {
+                diagnostics.push(
+                    line_number,
+                    format!("expected 'key=value', found '{}'", line),
+                    None,
+                );
                 continue;
-            }
+            };
 
-            match (parts[0], current_profile.as_ref()) {
-                ("ssh_config", None) => ssh_config_path = Some(PathBuf::from(parts[1])),
-                ("ghp_config", None) => ghp_config_path = Some(PathBuf::from(parts[1])),
-                ("username", Some(profile)) => {
-                    profiles.entry(profile.clone())
-                        .or_insert_with(|| Profile {
-                            username: String::new(),
-                            email: String::new(),
-                            ssh_key: PathBuf::new(),
-                        })
-                        .username = parts[1].to_string();
-                }
-                ("email", Some(profile)) => {
-                    profiles.entry(profile.clone())
-                        .or_insert_with(|| Profile {
-                            username: String::new(),
-                            email: String::new(),
-                            ssh_key: PathBuf::new(),
-                        })
-                        .email = parts[1].to_string();
+            match (key, current_profile.as_ref()) {
+                ("ssh_config", None) => ssh_config_path = PathBuf::from(value),
+                ("ghp_config", None) => ghp_config_path = PathBuf::from(value),
+                (key, None) if LEGACY_PROFILE_KEYS.contains(&key) => diagnostics.push(
+                    line_number,
+                    format!("'{}' must be inside a [profile] section", key),
+                    None,
+                ),
+                (key, None) => diagnostics.unknown_key(line_number, key, "at the top level", LEGACY_CONFIG_KEYS),
+                (key, Some(profile)) if LEGACY_PROFILE_KEYS.contains(&key) => {
+                    let fields = &mut sections.get_mut(profile).expect("section was created").1;
+                    if fields.insert(key.to_string(), (line_number, value.to_string())).is_some() {
+                        diagnostics.error(line_number, format!("'{}' is set more than once in profile '{}'", key, profile));
+                    }
                 }
-                ("ssh_key", Some(profile)) => {
-                    profiles.entry(profile.clone())
-                        .or_insert_with(|| Profile {
-                            username: String::new(),
-                            email: String::new(),
-                            ssh_key: PathBuf::new(),
-                        })
-                        .ssh_key = PathBuf::from(parts[1]);
+                (key, Some(profile)) => {
+                    diagnostics.unknown_key(line_number, key, &format!("in profile '{}'", profile), LEGACY_PROFILE_KEYS)
                 }
-                _ => {}
             }
         }
 
-        Ok(Self {
-            ssh_config_path: ssh_config_path.unwrap_or_else(|| get_default_paths().unwrap().0),
-            ghp_config_path: ghp_config_path.unwrap_or_else(|| get_default_paths().unwrap().1),
+        let mut profiles = BTreeMap::new();
+        for (name, (header_line, fields)) in sections {
+            let mut field = |key: &str| match fields.get(key) {
+                Some((line, value)) => {
+                    diagnostics.check_field(*line, &name, key, value, check_keys);
+                    value.clone()
+                }
+                None => {
+                    diagnostics.error(header_line, format!("profile '{}' is missing '{}'", name, key));
+                    String::new()
+                }
+            };
+            let profile = Profile::new(field("username"), field("email"), PathBuf::from(field("ssh_key")));
+            profiles.insert(name, profile);
+        }
+
+        diagnostics.finish(Self {
+            ssh_config_path,
+            ghp_config_path,
             profiles,
         })
     }
 
     fn save(&self) -> Result<()> {
-        let mut content = String::new();
-        content.push_str(&format!("ssh_config={}\n", self.ssh_config_path.display()));
-        content.push_str(&format!("ghp_config={}\n\n", self.ghp_config_path.display()));
+        self.save_to(&self.ghp_config_path)
+    }
+
+    fn save_to(&self, path: &Path) -> Result<()> {
+        write_file(path, self.to_toml())?;
+        Ok(())
+    }
 
+    fn to_toml(&self) -> String {
+        let mut profiles = toml::Table::new();
         for (name, profile) in &self.profiles {
-            content.push_str(&format!("[{}]\n", name));
-            content.push_str(&format!("username={}\n", profile.username));
-            content.push_str(&format!("email={}\n", profile.email));
-            content.push_str(&format!("ssh_key={}\n\n", profile.ssh_key.display()));
+            let mut fields = toml::Table::new();
+            fields.insert("username", toml::Value::String(profile.username.clone()));
+            fields.insert("email", toml::Value::String(profile.email.clone()));
+            fields.insert("ssh_key", toml::Value::String(profile.ssh_key.display().to_string()));
+            if profile.host != DEFAULT_HOST {
+                fields.insert("host", toml::Value::String(profile.host.clone()));
+            }
+            if let Some(port) = profile.ssh_port {
+                fields.insert("ssh_port", toml::Value::Integer(port.into()));
+            }
+            if let Some(user) = &profile.ssh_user {
+                fields.insert("ssh_user", toml::Value::String(user.clone()));
+            }
+            if !profile.ssh_options.is_empty() {
+                fields.insert("ssh_options", ssh_options_table(&profile.ssh_options));
+            }
+            if let Some(signing_key) = &profile.signing_key {
+                fields.insert("signing_key", toml::Value::String(signing_key.clone()));
+            }
+            if let Some(signing_format) = &profile.signing_format {
+                fields.insert("signing_format", toml::Value::String(signing_format.clone()));
+            }
+            if !profile.hosts.is_empty() {
+                let hosts = profile.hosts.iter()
+                    .map(|binding| {
+                        let mut table = toml::Table::new();
+                        table.insert("host", toml::Value::String(binding.host.clone()));
+                        table.insert("ssh_key", toml::Value::String(binding.ssh_key.display().to_string()));
+                        if let Some(port) = binding.ssh_port {
+                            table.insert("ssh_port", toml::Value::Integer(port.into()));
+                        }
+                        if let Some(user) = &binding.ssh_user {
+                            table.insert("ssh_user", toml::Value::String(user.clone()));
+                        }
+                        if !binding.ssh_options.is_empty() {
+                            table.insert("ssh_options", ssh_options_table(&binding.ssh_options));
+                        }
+                        toml::Value::Table(table)
+                    })
+                    .collect();
+                fields.insert("hosts", toml::Value::Array(hosts));
+            }
+            profiles.insert(name, toml::Value::Table(fields));
+        }
+
+        let mut table = toml::Table::new();
+        table.insert("version", toml::Value::Integer(CONFIG_VERSION));
+        table.insert("ssh_config", toml::Value::String(self.ssh_config_path.display().to_string()));
+        table.insert("ghp_config", toml::Value::String(self.ghp_config_path.display().to_string()));
+        table.insert("profiles", toml::Value::Table(profiles));
+        toml::to_string(&table)
+    }
+}
+
+fn ssh_options_table(options: &[(String, String)]) -> toml::Value {
+    let mut table = toml::Table::new();
+    for (keyword, value) in options {
+        table.insert(keyword, toml::Value::String(value.clone()));
+    }
+    toml::Value::Table(table)
+}
+
+/// Advisory lock on `<config>.lock`, held across a whole read-modify-write cycle
+/// so concurrent ghp invocations cannot clobber each other. Released on drop.
+/// A dry run writes nothing, so it takes no lock and creates neither the file nor its directory.
+struct ConfigLock {
+    _file: Option<fs::File>,
+}
+
+impl ConfigLock {
+    fn acquire(config_path: &Path) -> Result<Self> {
+        if dry_run() {
+            return Ok(Self { _file: None });
+        }
+        let mut lock_path = config_path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        if let Some(parent) = lock_path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => {
+                eprintln!("Waiting for another ghp process to release {}...", lock_path.display());
+                file.lock()?;
+            }
+            Err(fs::TryLockError::Error(err)) => return Err(err.into()),
+        }
+        Ok(Self { _file: Some(file) })
+    }
+}
+
+/// Set by `--dry-run`: writes are staged in memory and shown as diffs instead.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+/// Contents staged during a dry run; `None` marks a deleted file.
+static STAGED: Mutex<BTreeMap<PathBuf, Option<Vec<u8>>>> = Mutex::new(BTreeMap::new());
+
+fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+fn staged() -> std::sync::MutexGuard<'static, BTreeMap<PathBuf, Option<Vec<u8>>>> {
+    STAGED.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Reads `path`, seeing changes staged earlier in a dry run.
+fn read_file(path: &Path) -> io::Result<String> {
+    match staged().get(path) {
+        Some(Some(contents)) => Ok(String::from_utf8_lossy(contents).into_owned()),
+        Some(None) => Err(io::Error::from(io::ErrorKind::NotFound)),
+        None => fs::read_to_string(path),
+    }
+}
+
+fn write_file(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    if dry_run() {
+        staged().insert(path.to_path_buf(), Some(contents.as_ref().to_vec()));
+        return Ok(());
+    }
+    write_atomic(path, contents)
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    if dry_run() {
+        staged().insert(path.to_path_buf(), None);
+        return Ok(());
+    }
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Prints every change staged by a dry run as a unified diff against the file on disk.
+fn print_staged_diffs() {
+    let staged = staged();
+    let mut changed = false;
+    for (path, contents) in staged.iter() {
+        let old = fs::read(path).map(|old| String::from_utf8_lossy(&old).into_owned()).unwrap_or_default();
+        let new = contents.as_deref().map(String::from_utf8_lossy).unwrap_or_default();
+        let label = path.display().to_string();
+        let diff = diff::unified(&old, &new, &label, &label);
+        if !diff.is_empty() {
+            print!("{}", diff);
+            changed = true;
+        }
+    }
+    if !changed {
+        println!("No changes.");
+    }
+    println!("Dry run: nothing was written.");
+}
+
+/// Replaces `path` via a synced temporary file and a rename, so readers never see a
+/// partial write. Symlinks are followed, and the original mode and ownership are kept
+/// (SSH refuses configs that are group- or world-writable).
+fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match target.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        Some(parent) => parent.to_path_buf(),
+        None => PathBuf::from("."),
+    };
+    fs::create_dir_all(&dir)?;
+    let original = fs::metadata(&target).ok();
+
+    let file_name = target.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp_path = dir.join(format!(".{}.ghp-tmp.{}", file_name, std::process::id()));
+
+    let result = (|| -> Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp_path)?;
+        file.write_all(contents.as_ref())?;
+
+        #[cfg(unix)]
+        if let Some(original) = &original {
+            use std::os::unix::fs::MetadataExt;
+            file.set_permissions(original.permissions())?;
+            let current = file.metadata()?;
+            if current.uid() != original.uid() || current.gid() != original.gid() {
+                std::os::unix::fs::fchown(&file, Some(original.uid()), Some(original.gid()))?;
+            }
+        }
+        #[cfg(not(unix))]
+        if let Some(original) = &original {
+            file.set_permissions(original.permissions())?;
         }
 
-        fs::write(&self.ghp_config_path, content)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &target)?;
         Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+
+    #[cfg(unix)]
+    fs::File::open(&dir)?.sync_all()?;
+    Ok(())
+}
+
+/// The identity currently in effect, as seen by SSH and by git.
+struct ActiveIdentity {
+    /// The `IdentityFile` of each forge's canonical `Host` block.
+    ssh_keys: BTreeMap<String, Option<PathBuf>>,
+    git_username: Option<String>,
+    git_email: Option<String>,
+}
+
+impl ActiveIdentity {
+    fn detect(config: &Config) -> Result<Self> {
+        let ssh_config = read_ssh_config(&config.ssh_config_path);
+        let mut hosts: Vec<String> = config.profiles.values()
+            .flat_map(|profile| profile.bindings())
+            .map(|binding| binding.host)
+            .collect();
+        if hosts.is_empty() {
+            hosts.push(DEFAULT_HOST.to_string());
+        }
+        let ssh_keys = hosts.into_iter()
+            .map(|host| {
+                let ssh_key = find_host_identity(&ssh_config, &host)
+                    .map(|ssh_key| expand_path(&ssh_key).unwrap_or(ssh_key));
+                (host, ssh_key)
+            })
+            .collect();
+        Ok(Self {
+            ssh_keys,
+            git_username: git_global_config("user.name")?,
+            git_email: git_global_config("user.email")?,
+        })
+    }
+
+    /// Whether SSH uses the profile's key for every host it is bound to.
+    fn matches_ssh(&self, profile: &Profile) -> bool {
+        profile.bindings().iter().all(|binding| self.matches_binding(binding))
+    }
+
+    fn matches_binding(&self, binding: &HostBinding) -> bool {
+        match self.ssh_keys.get(&binding.host) {
+            Some(Some(ssh_key)) => binding.ssh_key_path().ok().as_ref() == Some(ssh_key),
+            _ => false,
+        }
+    }
+
+    /// The profile whose key SSH uses for `host`, preferring one git also agrees with.
+    fn host_profile<'a>(&self, config: &'a Config, host: &str) -> Option<&'a str> {
+        let candidates = || config.profiles.iter()
+            .filter(|(_, profile)| {
+                profile.bindings().iter().any(|binding| binding.host == host && self.matches_binding(binding))
+            });
+        candidates()
+            .find(|(_, profile)| self.matches_git(profile))
+            .or_else(|| candidates().next())
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn matches_git(&self, profile: &Profile) -> bool {
+        self.git_email.as_ref() == Some(&profile.email)
+            && self.git_username.as_ref().is_none_or(|username| &profile.username == username)
+    }
+
+    /// The profile whose key SSH uses, preferring one git also agrees with.
+    fn ssh_profile<'a>(&self, config: &'a Config) -> Option<&'a str> {
+        self.active_profile(config).or_else(|| {
+            config.profiles.iter()
+                .find(|(_, profile)| self.matches_ssh(profile))
+                .map(|(name, _)| name.as_str())
+        })
+    }
+
+    /// The profile git is configured for, preferring one SSH also agrees with.
+    fn git_profile<'a>(&self, config: &'a Config) -> Option<&'a str> {
+        self.active_profile(config).or_else(|| {
+            config.profiles.iter()
+                .find(|(_, profile)| self.matches_git(profile))
+                .map(|(name, _)| name.as_str())
+        })
+    }
+
+    /// The profile both SSH and git agree on, if any.
+    fn active_profile<'a>(&self, config: &'a Config) -> Option<&'a str> {
+        config.profiles.iter()
+            .find(|(_, profile)| self.matches_ssh(profile) && self.matches_git(profile))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
     }
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
     let matches = Command::new("ghp")
         .about("GitHub Profile Manager - Manage multiple GitHub profiles and SSH/GPG keys")
         .arg_required_else_help(true)
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .global(true)
+                .help("Show the changes as diffs instead of writing them")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .global(true)
+                .help("Path to the GHP config file (defaults to $GHP_CONFIG, then $XDG_CONFIG_HOME/ghp/config, then ~/.ghp)")
+                .value_parser(clap::value_parser!(String)),
+        )
         .subcommand(
             Command::new("setup")
                 .about("Initial setup for paths to config files")
@@ -156,6 +1040,81 @@ fn main() -> Result<()> {
                         .required(true)
                         .help("Name of the profile")
                         .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("username")
+                        .long("username")
+                        .help("Git username")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("email")
+                        .long("email")
+                        .help("Git email")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("ssh_key")
+                        .long("ssh-key")
+                        .help("Path to the SSH key")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("host")
+                        .long("host")
+                        .help("SSH host of the forge, e.g. gitlab.com, bitbucket.org or a GitHub Enterprise server [default: github.com]")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("ssh_port")
+                        .long("ssh-port")
+                        .help("SSH port of the host")
+                        .value_parser(clap::value_parser!(u16).range(1..)),
+                )
+                .arg(
+                    Arg::new("ssh_user")
+                        .long("ssh-user")
+                        .help("SSH user for the host [default: git]")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("ssh_option")
+                        .long("ssh-option")
+                        .value_name("KEY=VALUE")
+                        .help("Extra SSH directive for the profile's Host blocks, e.g. ProxyJump=bastion (repeatable)")
+                        .action(clap::ArgAction::Append)
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("signing_key")
+                        .long("signing-key")
+                        .help("Key to sign commits and tags with (git user.signingkey)")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("signing_format")
+                        .long("signing-format")
+                        .help("Signature format (git gpg.format) [default: openpgp]")
+                        .value_parser(["openpgp", "x509", "ssh"]),
+                )
+                .arg(
+                    Arg::new("portable")
+                        .long("portable")
+                        .help("Store the SSH key path relative to ~ so the config can be shared across machines")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("from_json")
+                        .long("from-json")
+                        .help("Read the profile as a JSON object from stdin")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .short('f')
+                        .help("Overwrite the profile if it already exists")
+                        .action(clap::ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -166,40 +1125,560 @@ fn main() -> Result<()> {
                         .required(true)
                         .help("Name of the profile")
                         .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("local")
+                        .long("local")
+                        .help("Only switch the current repository, via its .git/config and core.sshCommand")
+                        .action(clap::ArgAction::SetTrue),
                 ),
         )
         .subcommand(
-            Command::new("remove")
+            Command::new("edit")
+                .about("Edit an existing GitHub profile")
+                .arg(
+                    Arg::new("profile")
+                        .required(true)
+                        .help("Name of the profile")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("username")
+                        .long("username")
+                        .help("New Git username")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("email")
+                        .long("email")
+                        .help("New Git email")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("ssh_key")
+                        .long("ssh-key")
+                        .help("New path to the SSH key")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("host")
+                        .long("host")
+                        .help("New SSH host of the forge")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("ssh_port")
+                        .long("ssh-port")
+                        .help("New SSH port of the host")
+                        .value_parser(clap::value_parser!(u16).range(1..)),
+                )
+                .arg(
+                    Arg::new("ssh_user")
+                        .long("ssh-user")
+                        .help("New SSH user for the host")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("ssh_option")
+                        .long("ssh-option")
+                        .value_name("KEY=VALUE")
+                        .help("Extra SSH directive for the profile's Host blocks, e.g. ProxyJump=bastion (repeatable); an empty VALUE removes it")
+                        .action(clap::ArgAction::Append)
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("signing_key")
+                        .long("signing-key")
+                        .help("New key to sign commits and tags with; an empty value stops signing")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("signing_format")
+                        .long("signing-format")
+                        .help("New signature format; an empty value restores the default")
+                        .value_parser(["openpgp", "x509", "ssh", ""]),
+                )
+                .arg(
+                    Arg::new("portable")
+                        .long("portable")
+                        .help("Store the SSH key path relative to ~ so the config can be shared across machines")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("rename")
+                .about("Rename an existing GitHub profile")
+                .arg(
+                    Arg::new("old")
+                        .required(true)
+                        .help("Current name of the profile")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("new")
+                        .required(true)
+                        .help("New name of the profile")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .short('f')
+                        .help("Overwrite the target profile if it already exists")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("copy")
+                .about("Copy an existing GitHub profile under a new name")
+                .arg(
+                    Arg::new("src")
+                        .required(true)
+                        .help("Name of the profile to copy")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("dst")
+                        .required(true)
+                        .help("Name of the new profile")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .short('f')
+                        .help("Overwrite the target profile if it already exists")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("List all GitHub profiles, marking the active one"),
+        )
+        .subcommand(
+            Command::new("current")
+                .visible_alias("whoami")
+                .about("Show the active profile, failing if SSH and git disagree"),
+        )
+        .subcommand(
+            Command::new("ssh")
+                .about("Manage ghp's section of the SSH config")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("adopt")
+                        .about("Move existing blocks for ghp's forge hosts and aliases into its managed section"),
+                )
+                .subcommand(
+                    Command::new("dedupe")
+                        .about("Find duplicate or shadowed Host entries, including in included files, and consolidate them")
+                        .arg(
+                            Arg::new("yes")
+                                .long("yes")
+                                .short('y')
+                                .help("Consolidate duplicates without asking")
+                                .action(clap::ArgAction::SetTrue),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("bind")
+                .about("Use a profile for every repository under a directory, via includeIf in the global git config")
+                .arg(
+                    Arg::new("profile")
+                        .required(true)
+                        .help("Name of the profile")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("dir")
+                        .required(true)
+                        .help("Directory whose repositories use the profile")
+                        .value_parser(clap::value_parser!(String)),
+                ),
+        )
+        .subcommand(
+            Command::new("unbind")
+                .about("Stop using a profile for the repositories under a directory")
+                .arg(
+                    Arg::new("dir")
+                        .required(true)
+                        .help("Directory previously passed to `ghp bind`")
+                        .value_parser(clap::value_parser!(String)),
+                ),
+        )
+        .subcommand(
+            Command::new("bindings")
+                .about("List the directories bound to a profile"),
+        )
+        .subcommand(
+            Command::new("env")
+                .about("Print shell commands that switch the profile for the current shell session only")
+                .arg(
+                    Arg::new("profile")
+                        .help("Name of the profile [default: from .ghp-profile or a bound directory]")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("shell")
+                        .long("shell")
+                        .help("Shell syntax to print [default: bash]")
+                        .value_parser(["bash", "zsh", "fish"]),
+                ),
+        )
+        .subcommand(
+            Command::new("clone")
+                .about("Clone a repository through a profile's SSH alias and use the profile in it")
+                .arg(
+                    Arg::new("profile")
+                        .required(true)
+                        .help("Name of the profile")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("url")
+                        .required(true)
+                        .help("Repository to clone: an SSH or HTTPS URL, or owner/repo on the profile's forge")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("dir")
+                        .help("Directory to clone into [default: the repository name]")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .help("Also bind the new directory to the profile, as with `ghp bind`")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("exec")
+                .visible_alias("run")
+                .about("Run a single command as a profile, e.g. `ghp exec work -- git push`, without switching")
+                .arg(
+                    Arg::new("profile")
+                        .required(true)
+                        .help("Name of the profile")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("command")
+                        .required(true)
+                        .num_args(1..)
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true)
+                        .help("Command to run, with its arguments")
+                        .value_parser(clap::value_parser!(String)),
+                ),
+        )
+        .subcommand(
+            Command::new("hook")
+                .about("Print a shell hook that runs `ghp env` whenever the directory changes")
+                .arg(
+                    Arg::new("shell")
+                        .required(true)
+                        .help("Shell to print the hook for")
+                        .value_parser(["bash", "zsh", "fish"]),
+                ),
+        )
+        .subcommand(
+            Command::new("host")
+                .about("Manage the additional forge hosts a profile is bound to")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("add")
+                        .about("Bind a profile to another forge host with its own SSH key")
+                        .arg(
+                            Arg::new("profile")
+                                .required(true)
+                                .help("Name of the profile")
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            Arg::new("host")
+                                .required(true)
+                                .help("SSH host of the forge (e.g. gitlab.com)")
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            Arg::new("ssh_key")
+                                .long("ssh-key")
+                                .required(true)
+                                .help("Path to the SSH key for this host")
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            Arg::new("ssh_port")
+                                .long("ssh-port")
+                                .help("SSH port of the host")
+                                .value_parser(clap::value_parser!(u16).range(1..)),
+                        )
+                        .arg(
+                            Arg::new("ssh_user")
+                                .long("ssh-user")
+                                .help("SSH user for the host [default: git]")
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            Arg::new("ssh_option")
+                                .long("ssh-option")
+                                .value_name("KEY=VALUE")
+                                .help("Extra SSH directive for this host's blocks (repeatable)")
+                                .action(clap::ArgAction::Append)
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            Arg::new("portable")
+                                .long("portable")
+                                .help("Store the SSH key path relative to ~ so the config can be shared across machines")
+                                .action(clap::ArgAction::SetTrue),
+                        ),
+                )
+                .subcommand(
+                    Command::new("remove")
+                        .about("Unbind a profile from an additional forge host")
+                        .arg(
+                            Arg::new("profile")
+                                .required(true)
+                                .help("Name of the profile")
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            Arg::new("host")
+                                .required(true)
+                                .help("SSH host to unbind")
+                                .value_parser(clap::value_parser!(String)),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Inspect the GHP config file")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("check")
+                        .about("Validate the GHP config file and report every problem found"),
+                ),
+        )
+        .subcommand(
+            Command::new("backups")
+                .about("Inspect the backups taken before each change")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("List the available backups, newest first"),
+                ),
+        )
+        .subcommand(
+            Command::new("undo")
+                .about("Restore the SSH config, GHP config and git config from a backup")
+                .arg(
+                    Arg::new("id")
+                        .help("Backup to restore (defaults to the most recent one)")
+                        .value_parser(clap::value_parser!(String)),
+                ),
+        )
+        .subcommand(
+            Command::new("remove")
                 .about("Remove an existing GitHub profile")
                 .arg(
                     Arg::new("profile")
                         .required(true)
                         .help("Name of the profile")
                         .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("keep_ssh")
+                        .long("keep-ssh")
+                        .help("Leave the SSH config untouched")
+                        .action(clap::ArgAction::SetTrue),
                 ),
         )
         .get_matches();
 
-    match matches.subcommand() {
-        Some(("setup", sub_m)) => setup(sub_m),
-        Some(("add", sub_m)) => add_profile(sub_m),
-        Some(("switch", sub_m)) => switch_profile(sub_m),
-        Some(("remove", sub_m)) => remove_profile(sub_m),
+    DRY_RUN.store(matches.get_flag("dry_run"), Ordering::Relaxed);
+    let result = match matches.subcommand() {
+        Some(("setup", sub_m)) => run_mutating(sub_m, setup),
+        Some(("add", sub_m)) => run_mutating(sub_m, add_profile),
+        Some(("switch", sub_m)) => run_mutating(sub_m, switch_profile),
+        Some(("remove", sub_m)) => run_mutating(sub_m, remove_profile),
+        Some(("edit", sub_m)) => run_mutating(sub_m, edit_profile),
+        Some(("rename", sub_m)) => run_mutating(sub_m, rename_profile),
+        Some(("copy", sub_m)) => run_mutating(sub_m, copy_profile),
+        Some(("undo", sub_m)) => undo(sub_m),
+        Some(("ssh", sub_m)) => match sub_m.subcommand() {
+            Some(("adopt", adopt_m)) => run_mutating(adopt_m, adopt_ssh_hosts),
+            Some(("dedupe", dedupe_m)) => run_mutating(dedupe_m, dedupe_ssh_hosts),
+            _ => Err(GhpError::ConfigParse("Invalid subcommand".to_string())),
+        },
+        Some(("bind", sub_m)) => run_mutating(sub_m, bind_dir),
+        Some(("unbind", sub_m)) => run_mutating(sub_m, unbind_dir),
+        Some(("bindings", sub_m)) => list_bindings(sub_m),
+        Some(("env", sub_m)) => print_env(sub_m),
+        Some(("exec", sub_m)) => exec_profile(sub_m),
+        Some(("clone", sub_m)) => run_mutating(sub_m, clone_repo),
+        Some(("hook", sub_m)) => print_hook(sub_m),
+        Some(("host", sub_m)) => match sub_m.subcommand() {
+            Some(("add", add_m)) => run_mutating(add_m, add_host_binding),
+            Some(("remove", remove_m)) => run_mutating(remove_m, remove_host_binding),
+            _ => Err(GhpError::ConfigParse("Invalid subcommand".to_string())),
+        },
+        Some(("backups", sub_m)) => match sub_m.subcommand() {
+            Some(("list", _)) => list_backups(),
+            _ => Err(GhpError::ConfigParse("Invalid subcommand".to_string())),
+        },
+        Some(("list", sub_m)) => list_profiles(sub_m),
+        Some(("current", sub_m)) => current_profile(sub_m),
+        Some(("config", sub_m)) => match sub_m.subcommand() {
+            Some(("check", check_m)) => check_config(check_m),
+            _ => Err(GhpError::ConfigParse("Invalid subcommand".to_string())),
+        },
         _ => Err(GhpError::ConfigParse("Invalid subcommand".to_string())),
+    };
+    if result.is_ok() && dry_run() {
+        print_staged_diffs();
     }
+    result
 }
 
-fn get_default_paths() -> Result<(PathBuf, PathBuf)> {
-    let home = std::env::var("HOME")
+/// Runs a command that modifies files while holding the config lock, after taking
+/// a backup of every file it may touch. The backup is dropped if nothing changed.
+fn run_mutating(matches: &ArgMatches, command: fn(&ArgMatches) -> Result<()>) -> Result<()> {
+    let path = config_path(matches)?;
+    // Lock the file the command will write, not a `setup -g` redirect pointing at it.
+    let resolved = Config::resolve(&path)?;
+    let _lock = ConfigLock::acquire(&resolved)?;
+    // Read-only commands parse legacy files in memory; only locked commands rewrite them.
+    Config::migrate(&path)?;
+    Config::migrate(&resolved)?;
+    let config = Config::load(&path)?;
+
+    let mut files: Vec<PathBuf> = load_ssh_config_files(&config.ssh_config_path)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    files.push(config.ghp_config_path.clone());
+    files.push(git_global_config_path()?);
+    files.extend(git_local_config_path()?);
+    for profile_name in config.profiles.keys() {
+        files.push(profile_fragment_path(profile_name)?);
+    }
+    if dry_run() {
+        return command(matches);
+    }
+    let description = std::env::args().skip(1).collect::<Vec<_>>().join(" ");
+    let snapshot = backup::Snapshot::create(&files, &format!("ghp {}", description))?;
+
+    let result = command(matches);
+    snapshot.finish(result.is_ok())?;
+    result
+}
+
+fn home_dir() -> Result<PathBuf> {
+    std::env::var("HOME")
         .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| GhpError::ConfigParse("Could not determine home directory".to_string()))?;
-    let home = PathBuf::from(home);
+        .map(PathBuf::from)
+        .map_err(|_| GhpError::ConfigParse("Could not determine home directory".to_string()))
+}
+
+fn get_default_paths() -> Result<(PathBuf, PathBuf)> {
+    let home = home_dir()?;
     Ok((
         home.join(".ssh").join("config"),
         home.join(".ghp"),
     ))
 }
 
+/// Expands a leading `~` and any `$VAR` or `${VAR}` references in `path`.
+fn expand_path(path: &Path) -> Result<PathBuf> {
+    let Some(raw) = path.to_str() else {
+        return Ok(path.to_path_buf());
+    };
+    let home = || std::env::var("HOME")
+        .or_else(|_| std::env::var("USERPROFILE"))
+        .map_err(|_| GhpError::UndefinedVariable("HOME".to_string()));
+
+    let mut expanded = String::new();
+    let mut rest = raw;
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push_str(&home()?);
+        rest = &rest[1..];
+    }
+
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        let after = &rest[idx + 1..];
+        let (name, remainder) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", after),
+            }
+        } else {
+            let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        // A `$` that does not start a variable, as in `a$/b`, `${}` or an unterminated `${`, is kept as is.
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let value = std::env::var(name)
+            .map_err(|_| GhpError::UndefinedVariable(name.to_string()))?;
+        expanded.push_str(&value);
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+/// Rewrites `path` relative to `~` when it lies inside the home directory.
+fn contract_path(path: &Path) -> PathBuf {
+    let Ok(home) = expand_path(Path::new("~")) else {
+        return path.to_path_buf();
+    };
+    match path.strip_prefix(&home) {
+        Ok(relative) if relative.as_os_str().is_empty() => PathBuf::from("~"),
+        Ok(relative) => Path::new("~").join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Expands `raw` for storage, optionally in the portable `~`-relative form.
+fn normalize_ssh_key(raw: &str, portable: bool) -> Result<PathBuf> {
+    let expanded = expand_path(Path::new(raw))?;
+    Ok(if portable { contract_path(&expanded) } else { expanded })
+}
+
+fn xdg_config_home() -> Result<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => Ok(path),
+        _ => Ok(home_dir()?.join(".config")),
+    }
+}
+
+/// Finds the GHP config: `--config`, then `$GHP_CONFIG`, then
+/// `$XDG_CONFIG_HOME/ghp/config`, then the legacy `~/.ghp`.
+/// New configs are created in the XDG location.
+fn config_path(matches: &ArgMatches) -> Result<PathBuf> {
+    if let Some(path) = matches.get_one::<String>("config") {
+        return expand_path(Path::new(path));
+    }
+    if let Some(path) = std::env::var_os("GHP_CONFIG").filter(|path| !path.is_empty()) {
+        return expand_path(Path::new(&path));
+    }
+
+    let xdg = xdg_config_home()?.join("ghp").join("config");
+    let legacy = get_default_paths()?.1;
+    if xdg.exists() || !legacy.exists() {
+        Ok(xdg)
+    } else {
+        Ok(legacy)
+    }
+}
+
 fn read_input(prompt: &str) -> Result<String> {
     print!("{}", prompt);
     io::stdout().flush()?;
@@ -208,160 +1687,2121 @@ fn read_input(prompt: &str) -> Result<String> {
     Ok(input.trim().to_string())
 }
 
-fn setup(matches: &ArgMatches) -> Result<()> {
-    let ssh_config = matches.get_one::<String>("ssh_config")
-        .map(PathBuf::from)
-        .unwrap_or_else(|| get_default_paths().unwrap().0);
-    let ghp_config = matches.get_one::<String>("ghp_config")
-        .map(PathBuf::from)
-        .unwrap_or_else(|| get_default_paths().unwrap().1);
-
-    let config = Config {
-        ssh_config_path: ssh_config.clone(),
-        ghp_config_path: ghp_config.clone(),
-        profiles: HashMap::new(),
-    };
-    config.save()?;
-
-    println!("Configuration saved.");
-    println!("SSH config path: {}", ssh_config.display());
-    println!("GHP config path: {}", ghp_config.display());
-    Ok(())
+/// Uses `value` if given, otherwise prompts for it when stdin is a terminal.
+fn field_or_prompt(value: Option<String>, flag: &str, prompt: &str, interactive: bool) -> Result<String> {
+    match value {
+        Some(value) => Ok(value),
+        None if interactive => read_input(prompt),
+        None => Err(GhpError::MissingConfig(format!(
+            "{} is required when stdin is not a terminal",
+            flag
+        ))),
+    }
 }
 
-fn add_profile(matches: &ArgMatches) -> Result<()> {
-    let profile_name = matches.get_one::<String>("profile")
-        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
-    let mut config = Config::load()?;
-
-    let username = read_input("Enter Git username: ")?;
-    let email = read_input("Enter Git email: ")?;
-    let ssh_key = read_input("Enter path to SSH key: ")?;
+const PROFILE_JSON_KEYS: &[&str] = &[
+    "username", "email", "ssh_key", "host", "ssh_port", "ssh_user", "ssh_options", "signing_key", "signing_format", "hosts",
+];
 
-    if username.is_empty() || email.is_empty() || ssh_key.is_empty() {
-        return Err(GhpError::MissingConfig("All fields are required".to_string()));
+fn json_string(value: &json::Value, key: &str) -> Result<Option<String>> {
+    match value.get(key) {
+        None | Some(json::Value::Null) => Ok(None),
+        Some(json::Value::String(field)) => Ok(Some(field.clone())),
+        Some(other) => Err(GhpError::InvalidJson(format!(
+            "'{}' must be a string, found {}",
+            key,
+            other.type_name()
+        ))),
     }
+}
 
-    config.profiles.insert(profile_name.clone(), Profile {
-        username: username.clone(),
-        email,
-        ssh_key: PathBuf::from(ssh_key.clone()),
-    });
-
-    let ssh_config = format!(
-        "Host github.com-{}\n  HostName github.com\n  User git\n  IdentityFile {}\n\n",
-        profile_name, ssh_key
-    );
-    fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(&config.ssh_config_path)?
-        .write_all(ssh_config.as_bytes())?;
-
-    config.save()?;
-    println!("Profile '{}' added successfully!", profile_name);
-    Ok(())
+fn json_port(value: &json::Value, key: &str) -> Result<Option<u16>> {
+    match value.get(key) {
+        None | Some(json::Value::Null) => Ok(None),
+        Some(json::Value::Number(port)) if port.fract() == 0.0 && (1.0..=65535.0).contains(port) => Ok(Some(*port as u16)),
+        Some(other) => Err(GhpError::InvalidJson(format!(
+            "'{}' must be a port number between 1 and 65535, found {}",
+            key,
+            other.type_name()
+        ))),
+    }
 }
 
-fn switch_profile(matches: &ArgMatches) -> Result<()> {
-    let profile_name = matches.get_one::<String>("profile")
-        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
-    let config = Config::load()?;
+/// Reads an object of SSH options; numbers and booleans are converted like in the config file.
+fn json_ssh_options(value: &json::Value, key: &str) -> Result<Vec<(String, String)>> {
+    let entries = match value.get(key) {
+        None | Some(json::Value::Null) => return Ok(Vec::new()),
+        Some(json::Value::Object(entries)) => entries,
+        Some(other) => return Err(GhpError::InvalidJson(format!(
+            "'{}' must be an object, found {}",
+            key,
+            other.type_name()
+        ))),
+    };
+    entries.iter()
+        .map(|(keyword, option)| {
+            let option = match option {
+                json::Value::String(option) => option.clone(),
+                json::Value::Number(option) if option.fract() == 0.0 => format!("{}", option),
+                json::Value::Bool(option) => if *option { "yes" } else { "no" }.to_string(),
+                other => return Err(GhpError::InvalidJson(format!(
+                    "SSH option '{}' must be a string, found {}",
+                    keyword,
+                    other.type_name()
+                ))),
+            };
+            Ok((keyword.clone(), option))
+        })
+        .collect()
+}
 
-    let profile = config.profiles.get(profile_name)
-        .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+/// Reads an array of `{"host", "ssh_key", "ssh_port", "ssh_user", "ssh_options"}` objects.
+fn json_host_bindings(value: &json::Value, key: &str, portable: bool) -> Result<Vec<HostBinding>> {
+    let items = match value.get(key) {
+        None | Some(json::Value::Null) => return Ok(Vec::new()),
+        Some(json::Value::Array(items)) => items,
+        Some(other) => return Err(GhpError::InvalidJson(format!(
+            "'{}' must be an array, found {}",
+            key,
+            other.type_name()
+        ))),
+    };
+    items.iter()
+        .map(|item| {
+            let json::Value::Object(entries) = item else {
+                return Err(GhpError::InvalidJson(format!("each of '{}' must be an object, found {}", key, item.type_name())));
+            };
+            if let Some((field, _)) = entries.iter().find(|(field, _)| !HOST_BINDING_KEYS.contains(&field.as_str())) {
+                return Err(GhpError::InvalidJson(format!(
+                    "unknown key '{}' in '{}' (expected one of: {})",
+                    field,
+                    key,
+                    HOST_BINDING_KEYS.join(", ")
+                )));
+            }
+            let required = |field: &str| {
+                json_string(item, field)?.ok_or_else(|| GhpError::InvalidJson(format!("each of '{}' needs '{}'", key, field)))
+            };
+            Ok(HostBinding {
+                host: required("host")?,
+                ssh_key: normalize_ssh_key(&required("ssh_key")?, portable)?,
+                ssh_port: json_port(item, "ssh_port")?,
+                ssh_user: json_string(item, "ssh_user")?,
+                ssh_options: json_ssh_options(item, "ssh_options")?,
+            })
+        })
+        .collect()
+}
 
-    let ssh_content = fs::read_to_string(&config.ssh_config_path)
-        .unwrap_or_default();
-    
-    let new_host_config = format!(
-        "Host github.com\n  HostName github.com\n  User git\n  IdentityFile {}\n",
-        profile.ssh_key.display()
-    );
+/// Reads the repeated `--ssh-option KEY=VALUE` flags, in the order given.
+fn ssh_option_flags(matches: &ArgMatches) -> Result<Vec<(String, String)>> {
+    matches.get_many::<String>("ssh_option")
+        .unwrap_or_default()
+        .map(|option| {
+            option.split_once('=')
+                .map(|(keyword, value)| (keyword.trim().to_string(), value.trim().to_string()))
+                .ok_or_else(|| GhpError::InvalidSshOption(format!("expected KEY=VALUE, found {:?}", option)))
+        })
+        .collect()
+}
 
-    let updated_content = update_github_host_in_ssh_config(&ssh_content, &new_host_config)?;
-    fs::write(&config.ssh_config_path, updated_content)?;
+fn read_input_with_default(prompt: &str, current: &str) -> Result<String> {
+    let input = read_input(&format!("{} [{}]: ", prompt, current))?;
+    Ok(if input.is_empty() { current.to_string() } else { input })
+}
 
-    let output = std::process::Command::new("git")
-        .args(["config", "--global", "user.name", &profile.username])
-        .output()?;
-    if !output.status.success() {
-        return Err(GhpError::ConfigParse("Failed to set git username".to_string()));
+/// The file `git config --global` writes to.
+fn git_global_config_path() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("GIT_CONFIG_GLOBAL").filter(|path| !path.is_empty()) {
+        return Ok(PathBuf::from(path));
+    }
+    let home_config = home_dir()?.join(".gitconfig");
+    let xdg_config = xdg_config_home()?.join("git").join("config");
+    if !home_config.exists() && xdg_config.exists() {
+        Ok(xdg_config)
+    } else {
+        Ok(home_config)
     }
+}
 
+/// The config file of the repository containing the working directory, if any.
+fn git_local_config_path() -> Result<Option<PathBuf>> {
     let output = std::process::Command::new("git")
-        .args(["config", "--global", "user.email", &profile.email])
+        .args(["rev-parse", "--path-format=absolute", "--git-path", "config"])
+        .stderr(std::process::Stdio::null())
         .output()?;
     if !output.status.success() {
-        return Err(GhpError::ConfigParse("Failed to set git email".to_string()));
+        return Ok(None);
     }
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(Some(PathBuf::from(path)).filter(|path| !path.as_os_str().is_empty()))
+}
 
-    println!("Switched to profile '{}'", profile_name);
-    Ok(())
+fn set_git_global_config(entries: &[(&str, &str)]) -> Result<()> {
+    set_git_config(&git_global_config_path()?, entries)
+}
+
+fn set_git_config(path: &Path, entries: &[(&str, &str)]) -> Result<()> {
+    let edits: Vec<GitConfigEdit> = entries.iter().map(|(key, value)| GitConfigEdit::Set(key, value)).collect();
+    edit_git_config(path, &edits)
 }
 
-fn update_github_host_in_ssh_config(content: &str, new_host_config: &str) -> Result<String> {
-    let lines: Vec<&str> = content.lines().collect();
-    let mut start_idx = None;
-    let mut end_idx = None;
+/// A change to a git config file, applied with `git config --file`.
+enum GitConfigEdit<'a> {
+    Set(&'a str, &'a str),
+    /// Removes every value of the key, if there are any.
+    Unset(&'a str),
+    /// Removes the values of the key equal to the given one, if there are any.
+    UnsetValue(&'a str, &'a str),
+    RemoveSection(&'a str),
+}
 
-    for (i, line) in lines.iter().enumerate() {
-        let trimmed = line.trim();
-        if trimmed.eq_ignore_ascii_case("Host github.com") {
-            start_idx = Some(i);
-            let mut j = i + 1;
-            while j < lines.len() {
-                let next_line = lines[j].trim();
-                if next_line.starts_with("Host ") {
-                    end_idx = Some(j);
-                    break;
-                }
-                j += 1;
-            }
-            if end_idx.is_none() {
-                end_idx = Some(lines.len());
-            }
-            break;
+impl GitConfigEdit<'_> {
+    fn apply(&self, file: &Path) -> Result<()> {
+        let (args, action, target): (Vec<&str>, &str, &str) = match *self {
+            GitConfigEdit::Set(key, value) => (vec![key, value], "set", key),
+            GitConfigEdit::Unset(key) => (vec!["--unset-all", key], "unset", key),
+            GitConfigEdit::UnsetValue(key, value) => (vec!["--fixed-value", "--unset-all", key, value], "unset", key),
+            GitConfigEdit::RemoveSection(section) => (vec!["--remove-section", section], "remove", section),
+        };
+        let status = std::process::Command::new("git")
+            .args(["config", "--file"])
+            .arg(file)
+            .args(&args)
+            .output()?
+            .status;
+        // `git config` exits with 5 when there was nothing to unset.
+        let nothing_to_unset = action == "unset" && status.code() == Some(5);
+        if !status.success() && !nothing_to_unset {
+            return Err(GhpError::ConfigParse(format!("Failed to {} git {}", action, target)));
         }
+        Ok(())
     }
+}
 
-    let result = match (start_idx, end_idx) {
-        (Some(start), Some(end)) => {
-            let mut new_content = String::new();
-            if start > 0 {
-                new_content.push_str(&lines[..start].join("\n"));
-                new_content.push('\n');
-            }
-            new_content.push_str(new_host_config);
-            if end < lines.len() {
-                new_content.push('\n');
-                new_content.push_str(&lines[end..].join("\n"));
-            }
-            new_content
-        },
-        _ => {
-            let mut new_content = content.to_string();
-            if !new_content.is_empty() && !new_content.ends_with('\n') {
-                new_content.push('\n');
-            }
-            new_content.push_str(new_host_config);
-            new_content
-        }
-    };
+/// Applies `edits` to the git config file at `path`; in a dry run, applies them to a scratch copy instead.
+fn edit_git_config(path: &Path, edits: &[GitConfigEdit]) -> Result<()> {
+    if !dry_run() {
+        return edits.iter().try_for_each(|edit| edit.apply(path));
+    }
 
-    Ok(result)
+    let scratch = std::env::temp_dir().join(format!("ghp-dry-run-{}.gitconfig", std::process::id()));
+    fs::write(&scratch, read_file(path).unwrap_or_default())?;
+    let result = edits.iter().try_for_each(|edit| edit.apply(&scratch));
+    let contents = fs::read(&scratch);
+    let _ = fs::remove_file(&scratch);
+    result?;
+    write_file(path, contents?)
 }
 
-fn remove_profile(matches: &ArgMatches) -> Result<()> {
-    let profile_name = matches.get_one::<String>("profile")
+fn git_global_config(key: &str) -> Result<Option<String>> {
+    let output = std::process::Command::new("git")
+        .args(["config", "--global", "--get", key])
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(Some(value).filter(|value| !value.is_empty()))
+}
+
+fn setup(matches: &ArgMatches) -> Result<()> {
+    let discovered = config_path(matches)?;
+    let ssh_config = match matches.get_one::<String>("ssh_config") {
+        Some(path) => expand_path(Path::new(path))?,
+        None => get_default_paths()?.0,
+    };
+    let ghp_config = match matches.get_one::<String>("ghp_config") {
+        Some(path) => expand_path(Path::new(path))?,
+        None => discovered.clone(),
+    };
+
+    Config::migrate(&ghp_config)?;
+    let config = Config {
+        ssh_config_path: ssh_config.clone(),
+        ghp_config_path: ghp_config.clone(),
+        profiles: Config::read(&ghp_config)?.profiles,
+    };
+    config.save()?;
+
+    if ghp_config != discovered {
+        if Config::read(&discovered)?.profiles.is_empty() {
+            // Leave a redirect where ghp looks by default.
+            let redirect = Config {
+                profiles: BTreeMap::new(),
+                ..config
+            };
+            redirect.save_to(&discovered)?;
+        } else {
+            println!(
+                "Warning: {} still holds profiles; pass `--config {}` or set GHP_CONFIG to use the new file.",
+                discovered.display(),
+                ghp_config.display()
+            );
+        }
+    }
+
+    if !dry_run() {
+        println!("Configuration saved.");
+        println!("SSH config path: {}", ssh_config.display());
+        println!("GHP config path: {}", ghp_config.display());
+    }
+    Ok(())
+}
+
+fn validate_profile_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid {
+        return Err(GhpError::InvalidProfileName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn validate_profile(profile: &Profile) -> Result<()> {
+    if profile.username.is_empty() || profile.email.is_empty() || profile.ssh_key.as_os_str().is_empty() {
+        return Err(GhpError::MissingConfig("All fields are required".to_string()));
+    }
+    validate_username(&profile.username)?;
+    validate_email(&profile.email)?;
+    if let Some(signing_key) = &profile.signing_key {
+        validate_signing_key(signing_key)?;
+    }
+    if let Some(signing_format) = &profile.signing_format {
+        validate_signing_format(signing_format)?;
+    }
+    let bindings = profile.bindings();
+    for (index, binding) in bindings.iter().enumerate() {
+        validate_host(&binding.host)?;
+        if bindings[..index].iter().any(|other| other.host.eq_ignore_ascii_case(&binding.host)) {
+            return Err(GhpError::DuplicateHost(binding.host.clone()));
+        }
+        if let Some(ssh_user) = &binding.ssh_user {
+            validate_ssh_user(ssh_user)?;
+        }
+        for (index, (keyword, value)) in binding.ssh_options.iter().enumerate() {
+            validate_ssh_option(keyword, value)?;
+            if binding.ssh_options[..index].iter().any(|(other, _)| other.eq_ignore_ascii_case(keyword)) {
+                return Err(GhpError::InvalidSshOption(format!("'{}' is set more than once", keyword)));
+            }
+        }
+        validate_ssh_key(&binding.ssh_key_path()?)?;
+    }
+    Ok(())
+}
+
+fn validate_host(host: &str) -> Result<()> {
+    let valid = !host.is_empty()
+        && !host.starts_with(['-', '.'])
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-'));
+    if !valid {
+        return Err(GhpError::InvalidHost(host.to_string()));
+    }
+    Ok(())
+}
+
+fn validate_ssh_user(ssh_user: &str) -> Result<()> {
+    if ssh_user.is_empty() || ssh_user.contains(|c: char| c.is_whitespace() || c.is_control() || c == '"') {
+        return Err(GhpError::InvalidSshUser(ssh_user.to_string()));
+    }
+    Ok(())
+}
+
+/// Directives ghp writes itself or that would break the generated block.
+const RESERVED_SSH_OPTIONS: &[&str] = &["Host", "Match", "Include", "IdentityFile", "User", "Port"];
+
+fn validate_ssh_option(keyword: &str, value: &str) -> Result<()> {
+    if keyword.is_empty() || !keyword.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(GhpError::InvalidSshOption(format!("{:?} is not an SSH keyword", keyword)));
+    }
+    if let Some(reserved) = RESERVED_SSH_OPTIONS.iter().find(|reserved| reserved.eq_ignore_ascii_case(keyword)) {
+        return Err(GhpError::InvalidSshOption(format!(
+            "'{}' cannot be set as an option; use the profile's ssh_key, ssh_port or ssh_user instead",
+            reserved
+        )));
+    }
+    if value.trim().is_empty() || value.contains(|c: char| c.is_control()) {
+        return Err(GhpError::InvalidSshOption(format!("{:?} is not a valid value for '{}'", value, keyword)));
+    }
+    Ok(())
+}
+
+fn validate_signing_key(signing_key: &str) -> Result<()> {
+    if signing_key.trim().is_empty() || signing_key.contains(|c: char| c.is_control()) {
+        return Err(GhpError::InvalidSigningKey(signing_key.to_string()));
+    }
+    Ok(())
+}
+
+fn validate_signing_format(signing_format: &str) -> Result<()> {
+    if !["openpgp", "x509", "ssh"].contains(&signing_format) {
+        return Err(GhpError::InvalidSigningFormat(signing_format.to_string()));
+    }
+    Ok(())
+}
+
+fn validate_username(username: &str) -> Result<()> {
+    if username.contains(['\n', '\r', '=', '[']) {
+        return Err(GhpError::InvalidUsername(username.to_string()));
+    }
+    Ok(())
+}
+
+fn validate_email(email: &str) -> Result<()> {
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.contains(|c: char| c.is_whitespace() || c.is_control() || matches!(c, '=' | '[' | '<' | '>'))
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(GhpError::InvalidEmail(email.to_string()));
+    }
+    Ok(())
+}
+
+fn validate_ssh_key(ssh_key: &Path) -> Result<()> {
+    if !ssh_key.is_file() {
+        return Err(GhpError::SshKeyNotFound(ssh_key.to_path_buf()));
+    }
+
+    let mut head = [0u8; 16];
+    let read = fs::File::open(ssh_key)?.read(&mut head)?;
+    let public_prefixes: [&[u8]; 3] = [b"ssh-", b"ecdsa-", b"sk-"];
+    if ssh_key.extension().is_some_and(|ext| ext == "pub")
+        || public_prefixes.iter().any(|prefix| head[..read].starts_with(prefix))
+    {
+        return Err(GhpError::SshKeyIsPublic(ssh_key.to_path_buf()));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(ssh_key)?.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            return Err(GhpError::UnsafeKeyPermissions(ssh_key.to_path_buf(), mode));
+        }
+    }
+    Ok(())
+}
+
+fn add_profile(matches: &ArgMatches) -> Result<()> {
+    let profile_name = matches.get_one::<String>("profile")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let mut config = Config::load(&config_path(matches)?)?;
+
+    let mut username = matches.get_one::<String>("username").cloned();
+    let mut email = matches.get_one::<String>("email").cloned();
+    let mut ssh_key = matches.get_one::<String>("ssh_key").cloned();
+    let mut host = matches.get_one::<String>("host").cloned();
+    let mut ssh_port = matches.get_one::<u16>("ssh_port").copied();
+    let mut ssh_user = matches.get_one::<String>("ssh_user").cloned();
+    let mut ssh_options = ssh_option_flags(matches)?;
+    let mut signing_key = matches.get_one::<String>("signing_key").cloned();
+    let mut signing_format = matches.get_one::<String>("signing_format").cloned();
+    let mut hosts = Vec::new();
+
+    let from_json = matches.get_flag("from_json");
+    if from_json {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        let value = json::parse(&input).map_err(GhpError::InvalidJson)?;
+        let json::Value::Object(entries) = &value else {
+            return Err(GhpError::InvalidJson(format!("expected an object, found {}", value.type_name())));
+        };
+        if let Some((key, _)) = entries.iter().find(|(key, _)| !PROFILE_JSON_KEYS.contains(&key.as_str())) {
+            return Err(GhpError::InvalidJson(format!(
+                "unknown key '{}' (expected one of: {})",
+                key,
+                PROFILE_JSON_KEYS.join(", ")
+            )));
+        }
+        username = username.or(json_string(&value, "username")?);
+        email = email.or(json_string(&value, "email")?);
+        ssh_key = ssh_key.or(json_string(&value, "ssh_key")?);
+        host = host.or(json_string(&value, "host")?);
+        ssh_port = ssh_port.or(json_port(&value, "ssh_port")?);
+        ssh_user = ssh_user.or(json_string(&value, "ssh_user")?);
+        if ssh_options.is_empty() {
+            ssh_options = json_ssh_options(&value, "ssh_options")?;
+        }
+        signing_key = signing_key.or(json_string(&value, "signing_key")?);
+        signing_format = signing_format.or(json_string(&value, "signing_format")?);
+        hosts = json_host_bindings(&value, "hosts", matches.get_flag("portable"))?;
+    }
+
+    let interactive = !from_json && io::stdin().is_terminal();
+    let username = field_or_prompt(username, "--username", "Enter Git username: ", interactive)?;
+    let email = field_or_prompt(email, "--email", "Enter Git email: ", interactive)?;
+    let ssh_key = field_or_prompt(ssh_key, "--ssh-key", "Enter path to SSH key: ", interactive)?;
+
+    let mut profile = Profile::new(username, email, normalize_ssh_key(&ssh_key, matches.get_flag("portable"))?);
+    if let Some(host) = host {
+        profile.host = host;
+    }
+    profile.ssh_port = ssh_port;
+    profile.ssh_user = ssh_user;
+    profile.ssh_options = ssh_options;
+    profile.signing_key = signing_key;
+    profile.signing_format = signing_format;
+    profile.hosts = hosts;
+    validate_profile_name(profile_name)?;
+    validate_profile(&profile)?;
+    let old_profile = match config.profiles.get(profile_name) {
+        Some(_) if !matches.get_flag("force") => return Err(GhpError::ProfileExists(profile_name.clone())),
+        old_profile => old_profile.cloned(),
+    };
+    let aliases: Vec<String> = profile.bindings().iter().map(|binding| binding.alias(profile_name)).collect();
+    update_managed_ssh_config(&config.ssh_config_path, |managed| {
+        if let Some(old_profile) = &old_profile {
+            remove_alias_blocks(managed, profile_name, old_profile);
+        }
+        set_alias_blocks(managed, profile_name, &profile)
+    })?;
+    refresh_profile_fragment(profile_name, &profile)?;
+    config.profiles.insert(profile_name.clone(), profile);
+    let files = load_ssh_config_files(&config.ssh_config_path);
+    for host_block in ssh_host_blocks(&files) {
+        let (path, ssh_config) = &files[host_block.file];
+        if ssh_config.is_managed(&host_block.block) {
+            continue;
+        }
+        for alias in aliases.iter().filter(|alias| host_block.block.names(alias)) {
+            println!(
+                "Warning: {}:{} also defines 'Host {}'; run `ghp ssh dedupe` to consolidate them.",
+                path.display(),
+                host_block.block.line(),
+                alias
+            );
+        }
+    }
+
+    config.save()?;
+    if !dry_run() {
+        println!("Profile '{}' added successfully!", profile_name);
+    }
+    Ok(())
+}
+
+fn edit_profile(matches: &ArgMatches) -> Result<()> {
+    let profile_name = matches.get_one::<String>("profile")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let mut config = Config::load(&config_path(matches)?)?;
+
+    let was_active = ActiveIdentity::detect(&config)?
+        .active_profile(&config) == Some(profile_name.as_str());
+    let profile = config.profiles.get_mut(profile_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+    let old_profile = profile.clone();
+
+    let username = matches.get_one::<String>("username");
+    let email = matches.get_one::<String>("email");
+    let mut ssh_key = matches.get_one::<String>("ssh_key").cloned();
+    let portable = matches.get_flag("portable");
+    let host = matches.get_one::<String>("host");
+    let ssh_port = matches.get_one::<u16>("ssh_port");
+    let ssh_user = matches.get_one::<String>("ssh_user");
+    let ssh_options = ssh_option_flags(matches)?;
+    let signing_key = matches.get_one::<String>("signing_key");
+    let signing_format = matches.get_one::<String>("signing_format");
+
+    if let Some(host) = host {
+        profile.host = host.clone();
+    }
+    if let Some(ssh_port) = ssh_port {
+        profile.ssh_port = Some(*ssh_port);
+    }
+    if let Some(ssh_user) = ssh_user {
+        profile.ssh_user = Some(ssh_user.clone());
+    }
+    // `--ssh-option KEY=` with an empty value drops the option.
+    for (keyword, value) in &ssh_options {
+        profile.ssh_options.retain(|(option, _)| !option.eq_ignore_ascii_case(keyword));
+        if !value.is_empty() {
+            profile.ssh_options.push((keyword.clone(), value.clone()));
+        }
+    }
+    let connection_changed = host.is_some() || ssh_port.is_some() || ssh_user.is_some() || !ssh_options.is_empty();
+    // An empty value clears the signing setting.
+    if let Some(signing_key) = signing_key {
+        profile.signing_key = Some(signing_key.clone()).filter(|signing_key| !signing_key.is_empty());
+    }
+    if let Some(signing_format) = signing_format {
+        profile.signing_format = Some(signing_format.clone()).filter(|signing_format| !signing_format.is_empty());
+    }
+    let signing_changed = signing_key.is_some() || signing_format.is_some();
+
+    if username.is_none() && email.is_none() && ssh_key.is_none() && !portable && !connection_changed && !signing_changed {
+        profile.username = read_input_with_default("Enter Git username", &profile.username)?;
+        profile.email = read_input_with_default("Enter Git email", &profile.email)?;
+        let current_key = profile.ssh_key.display().to_string();
+        let new_key = read_input_with_default("Enter path to SSH key", &current_key)?;
+        if new_key != current_key {
+            ssh_key = Some(new_key);
+        }
+    } else {
+        if let Some(username) = username {
+            profile.username = username.clone();
+        }
+        if let Some(email) = email {
+            profile.email = email.clone();
+        }
+    }
+    if ssh_key.is_some() || portable {
+        let raw_key = ssh_key.unwrap_or_else(|| profile.ssh_key.display().to_string());
+        profile.ssh_key = normalize_ssh_key(&raw_key, portable)?;
+    }
+
+    validate_profile(profile)?;
+
+    update_managed_ssh_config(&config.ssh_config_path, |managed| {
+        remove_alias_blocks(managed, profile_name, &old_profile);
+        set_alias_blocks(managed, profile_name, profile)
+    })?;
+    refresh_profile_fragment(profile_name, profile)?;
+
+    config.save()?;
+    if !dry_run() {
+        println!("Profile '{}' updated successfully!", profile_name);
+    }
+    if was_active {
+        println!("Profile '{}' is active; run `ghp switch {}` to apply the changes.", profile_name, profile_name);
+    }
+    Ok(())
+}
+
+fn rename_profile(matches: &ArgMatches) -> Result<()> {
+    let old_name = matches.get_one::<String>("old")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let new_name = matches.get_one::<String>("new")
+        .ok_or_else(|| GhpError::MissingConfig("New profile name required".to_string()))?;
+    let mut config = Config::load(&config_path(matches)?)?;
+
+    if !config.profiles.contains_key(old_name) {
+        return Err(GhpError::ProfileNotFound(old_name.clone()));
+    }
+    validate_profile_name(new_name)?;
+    if old_name == new_name {
+        return Ok(());
+    }
+    if config.profiles.contains_key(new_name) && !matches.get_flag("force") {
+        return Err(GhpError::ProfileExists(new_name.clone()));
+    }
+
+    let profile = config.profiles.remove(old_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(old_name.clone()))?;
+
+    let replaced = config.profiles.get(new_name);
+    update_managed_ssh_config(&config.ssh_config_path, |managed| {
+        remove_alias_blocks(managed, old_name, &profile);
+        if let Some(replaced) = replaced {
+            remove_alias_blocks(managed, new_name, replaced);
+        }
+        set_alias_blocks(managed, new_name, &profile)
+    })?;
+    rebind_profile(old_name, Some((new_name, &profile)))?;
+    refresh_profile_fragment(new_name, &profile)?;
+
+    config.profiles.insert(new_name.clone(), profile);
+    config.save()?;
+    if !dry_run() {
+        println!("Profile '{}' renamed to '{}'", old_name, new_name);
+    }
+    Ok(())
+}
+
+fn copy_profile(matches: &ArgMatches) -> Result<()> {
+    let src_name = matches.get_one::<String>("src")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let dst_name = matches.get_one::<String>("dst")
+        .ok_or_else(|| GhpError::MissingConfig("New profile name required".to_string()))?;
+    let mut config = Config::load(&config_path(matches)?)?;
+
+    let profile = config.profiles.get(src_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(src_name.clone()))?
+        .clone();
+    validate_profile_name(dst_name)?;
+    if src_name == dst_name
+        || (config.profiles.contains_key(dst_name) && !matches.get_flag("force"))
+    {
+        return Err(GhpError::ProfileExists(dst_name.clone()));
+    }
+
+    let replaced = config.profiles.get(dst_name);
+    update_managed_ssh_config(&config.ssh_config_path, |managed| {
+        if let Some(replaced) = replaced {
+            remove_alias_blocks(managed, dst_name, replaced);
+        }
+        set_alias_blocks(managed, dst_name, &profile)
+    })?;
+
+    refresh_profile_fragment(dst_name, &profile)?;
+    config.profiles.insert(dst_name.clone(), profile);
+    config.save()?;
+    if !dry_run() {
+        println!("Profile '{}' copied to '{}'", src_name, dst_name);
+    }
+    Ok(())
+}
+
+fn add_host_binding(matches: &ArgMatches) -> Result<()> {
+    let profile_name = matches.get_one::<String>("profile")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let host = matches.get_one::<String>("host")
+        .ok_or_else(|| GhpError::MissingConfig("Host required".to_string()))?;
+    let ssh_key = matches.get_one::<String>("ssh_key")
+        .ok_or_else(|| GhpError::MissingConfig("SSH key required".to_string()))?;
+    let mut config = Config::load(&config_path(matches)?)?;
+
+    let was_active = ActiveIdentity::detect(&config)?
+        .active_profile(&config) == Some(profile_name.as_str());
+    let stale = ssh_option_keywords(&config);
+    let profile = config.profiles.get_mut(profile_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+    let binding = HostBinding {
+        host: host.clone(),
+        ssh_key: normalize_ssh_key(ssh_key, matches.get_flag("portable"))?,
+        ssh_port: matches.get_one::<u16>("ssh_port").copied(),
+        ssh_user: matches.get_one::<String>("ssh_user").cloned(),
+        ssh_options: ssh_option_flags(matches)?,
+    };
+    profile.hosts.push(binding.clone());
+    validate_profile(profile)?;
+
+    update_managed_ssh_config(&config.ssh_config_path, |managed| {
+        set_host_block(managed, &binding.alias(profile_name), &binding, &[])?;
+        // An active profile keeps using its own key on the new host, as after `ghp switch`.
+        if was_active {
+            set_host_block(managed, &binding.host, &binding, &stale)?;
+        }
+        Ok(())
+    })?;
+
+    config.save()?;
+    if !dry_run() {
+        println!("Host '{}' added to profile '{}'", host, profile_name);
+    }
+    Ok(())
+}
+
+fn remove_host_binding(matches: &ArgMatches) -> Result<()> {
+    let profile_name = matches.get_one::<String>("profile")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let host = matches.get_one::<String>("host")
+        .ok_or_else(|| GhpError::MissingConfig("Host required".to_string()))?;
+    let mut config = Config::load(&config_path(matches)?)?;
+
+    let was_active = ActiveIdentity::detect(&config)?
+        .active_profile(&config) == Some(profile_name.as_str());
+    let profile = config.profiles.get_mut(profile_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+    if profile.host.eq_ignore_ascii_case(host) {
+        return Err(GhpError::MissingConfig(format!(
+            "'{}' is the primary host of profile '{}'; change it with `ghp edit {} --host`",
+            host, profile_name, profile_name
+        )));
+    }
+    let index = profile.hosts.iter()
+        .position(|binding| binding.host.eq_ignore_ascii_case(host))
+        .ok_or_else(|| GhpError::MissingConfig(format!("profile '{}' is not bound to host '{}'", profile_name, host)))?;
+    let binding = profile.hosts.remove(index);
+
+    update_managed_ssh_config(&config.ssh_config_path, |managed| {
+        managed.remove_host(&binding.alias(profile_name));
+        if was_active {
+            managed.remove_host(&binding.host);
+        }
+        Ok(())
+    })?;
+
+    config.save()?;
+    if !dry_run() {
+        println!("Host '{}' removed from profile '{}'", binding.host, profile_name);
+    }
+    Ok(())
+}
+
+fn switch_profile(matches: &ArgMatches) -> Result<()> {
+    let profile_name = matches.get_one::<String>("profile")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let config = Config::load(&config_path(matches)?)?;
+
+    let profile = config.profiles.get(profile_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+
+    if matches.get_flag("local") {
+        return switch_local(profile_name, profile);
+    }
+
+    // Every canonical host block is rewritten in a single write of the SSH config. Hosts that only
+    // other profiles are bound to lose theirs, so no other profile's key stays in use there.
+    let bindings = profile.bindings();
+    let stale = ssh_option_keywords(&config);
+    let other_hosts: Vec<String> = config.profiles.values()
+        .flat_map(|other| other.bindings())
+        .map(|binding| binding.host)
+        .filter(|host| !bindings.iter().any(|binding| binding.host.eq_ignore_ascii_case(host)))
+        .collect();
+    let ssh_config = update_managed_ssh_config(&config.ssh_config_path, |managed| {
+        for host in &other_hosts {
+            managed.remove_host(host);
+        }
+        bindings.iter().try_for_each(|binding| set_host_block(managed, &binding.host, binding, &stale))
+    })?;
+    for block in ssh_config.blocks() {
+        for binding in bindings.iter().filter(|binding| block.names(&binding.host)) {
+            if !ssh_config.is_managed(&block) {
+                println!(
+                    "Warning: {}:{} also configures {} outside ghp's managed section; run `ghp ssh adopt` to merge it.",
+                    config.ssh_config_path.display(),
+                    block.line(),
+                    binding.host
+                );
+            }
+        }
+    }
+
+    write_git_identity(&git_global_config_path()?, &git_identity(profile))?;
+
+    if !dry_run() {
+        println!("Switched to profile '{}'", profile_name);
+    }
+    Ok(())
+}
+
+/// Points the current repository at the profile, leaving the SSH config and global git config alone.
+fn switch_local(profile_name: &str, profile: &Profile) -> Result<()> {
+    let path = git_local_config_path()?
+        .ok_or_else(|| GhpError::MissingConfig("--local must be run inside a git repository".to_string()))?;
+    write_local_identity(&path, profile, &repo_binding(profile))?;
+
+    if !dry_run() {
+        println!("Switched to profile '{}' for this repository", profile_name);
+    }
+    Ok(())
+}
+
+/// Writes the profile's identity and the binding's key into a repository's own config file.
+fn write_local_identity(path: &Path, profile: &Profile, binding: &HostBinding) -> Result<()> {
+    let mut entries = layered_git_identity(profile);
+    entries.push(("core.sshCommand", ssh_command(binding)?));
+    write_git_identity(path, &entries)
+}
+
+/// Sets `entries` in the git config at `path` and unsets the signing settings they leave out.
+fn write_git_identity(path: &Path, entries: &[(&str, String)]) -> Result<()> {
+    let mut edits: Vec<GitConfigEdit> = entries.iter().map(|(key, value)| GitConfigEdit::Set(key, value)).collect();
+    edits.extend(
+        SIGNING_GIT_KEYS.iter()
+            .filter(|key| !entries.iter().any(|(set, _)| set == *key))
+            .map(|key| GitConfigEdit::Unset(key)),
+    );
+    edit_git_config(path, &edits)
+}
+
+/// Git settings that sign commits and tags; cleared where a profile without a signing key takes over.
+const SIGNING_GIT_KEYS: &[&str] = &["user.signingkey", "gpg.format", "commit.gpgsign", "tag.gpgsign"];
+
+/// The git settings that make commits authored, and signed if configured, as `profile`.
+fn git_identity(profile: &Profile) -> Vec<(&'static str, String)> {
+    let mut entries = vec![("user.name", profile.username.clone()), ("user.email", profile.email.clone())];
+    if let Some(signing_key) = &profile.signing_key {
+        entries.push(("user.signingkey", signing_key.clone()));
+        if let Some(signing_format) = &profile.signing_format {
+            entries.push(("gpg.format", signing_format.clone()));
+        }
+        entries.push(("commit.gpgsign", "true".to_string()));
+        entries.push(("tag.gpgsign", "true".to_string()));
+    }
+    entries
+}
+
+/// Like `git_identity`, for settings layered over the global git config, where leaving a signing
+/// setting out would inherit another identity's: without a signing key, signing is turned off,
+/// and with one, `gpg.format` is always given.
+fn layered_git_identity(profile: &Profile) -> Vec<(&'static str, String)> {
+    let mut entries = git_identity(profile);
+    if profile.signing_key.is_none() {
+        entries.push(("commit.gpgsign", "false".to_string()));
+        entries.push(("tag.gpgsign", "false".to_string()));
+    } else if profile.signing_format.is_none() {
+        let at = entries.iter().position(|(key, _)| *key == "user.signingkey").map_or(entries.len(), |index| index + 1);
+        entries.insert(at, ("gpg.format", DEFAULT_SIGNING_FORMAT.to_string()));
+    }
+    entries
+}
+
+/// The binding for the forge the repository's `origin` points at, falling back to the primary one.
+fn repo_binding(profile: &Profile) -> HostBinding {
+    let mut bindings = profile.bindings();
+    let output = std::process::Command::new("git")
+        .args(["remote", "get-url", "origin"])
+        .stderr(std::process::Stdio::null())
+        .output();
+    let remote_host = match &output {
+        Ok(output) if output.status.success() => url_host(String::from_utf8_lossy(&output.stdout).trim()),
+        _ => None,
+    };
+    let index = remote_host
+        .and_then(|remote_host| {
+            bindings.iter().position(|binding| {
+                remote_host.eq_ignore_ascii_case(&binding.host)
+                    || remote_host.to_ascii_lowercase().starts_with(&format!("{}-", binding.host.to_ascii_lowercase()))
+            })
+        })
+        .unwrap_or(0);
+    bindings.swap_remove(index)
+}
+
+/// The host of an SSH remote URL, either `[user@]host:path` or `ssh://[user@]host[:port]/path`.
+fn url_host(url: &str) -> Option<String> {
+    let authority = match url.strip_prefix("ssh://") {
+        Some(rest) => {
+            let authority = rest.split('/').next()?;
+            authority.rsplit_once(':').map_or(authority, |(host, _)| host)
+        }
+        None if !url.contains("://") => url.split_once(':')?.0,
+        None => return None,
+    };
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    Some(host.to_string()).filter(|host| !host.is_empty())
+}
+
+/// An ssh invocation that authenticates with only the binding's key, for `core.sshCommand`.
+fn ssh_command(binding: &HostBinding) -> Result<String> {
+    let ssh_key = binding.ssh_key_path()?.display().to_string();
+    Ok(format!("ssh -i {} -o IdentitiesOnly=yes", shell_quote(&ssh_key)))
+}
+
+/// Quotes `value` for a POSIX shell, leaving simple words as they are.
+fn shell_quote(value: &str) -> String {
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "-_./~+=:@%,".contains(c)) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// A directory whose repositories use a profile through an `includeIf` in the global git config.
+struct DirBinding {
+    /// As written in `gitdir:`, with a trailing slash.
+    dir: String,
+    profile: String,
+}
+
+/// The git config fragment that bound directories include, holding the profile's identity.
+fn profile_fragment_path(profile_name: &str) -> Result<PathBuf> {
+    Ok(xdg_config_home()?.join("ghp").join("profiles").join(format!("{}.gitconfig", profile_name)))
+}
+
+fn render_profile_fragment(profile_name: &str, profile: &Profile) -> Result<String> {
+    let mut entries = layered_git_identity(profile);
+    entries.push(("core.sshCommand", ssh_command(&profile.bindings()[0])?));
+
+    let mut out = format!("# Generated by ghp for profile '{}'; use `ghp edit` rather than changing it here.\n", profile_name);
+    let mut current_section = "";
+    for (key, value) in &entries {
+        let (section, name) = key.split_once('.').expect("git config keys have a section");
+        if section != current_section {
+            out.push_str(&format!("[{}]\n", section));
+            current_section = section;
+        }
+        out.push_str(&format!("\t{} = {}\n", name, git_config_value(value)));
+    }
+    Ok(out)
+}
+
+/// Quotes a git config value when it would otherwise be misread.
+fn git_config_value(value: &str) -> String {
+    if value.trim() == value && !value.contains(['"', '\\', ';', '#']) {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Rewrites the profile's fragment if any directory includes it.
+fn refresh_profile_fragment(profile_name: &str, profile: &Profile) -> Result<()> {
+    let path = profile_fragment_path(profile_name)?;
+    if read_file(&path).is_ok() {
+        write_file(&path, render_profile_fragment(profile_name, profile)?)?;
+    }
+    Ok(())
+}
+
+/// Every `includeIf` entry of the global git config, as `(key, value)` with the key lowercased
+/// except for its `gitdir:` condition.
+fn git_include_entries() -> Result<Vec<(String, String)>> {
+    let output = std::process::Command::new("git")
+        .args(["config", "--null", "--file"])
+        .arg(git_global_config_path()?)
+        .args(["--get-regexp", r"^includeif\."])
+        .output()?;
+    if !output.status.success() {
+        return Ok(Vec::new());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split('\0')
+        .filter_map(|entry| entry.split_once('\n'))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect())
+}
+
+/// The directories bound with `ghp bind`, found by the `includeIf` entries that point at a fragment.
+fn dir_bindings() -> Result<Vec<DirBinding>> {
+    let fragments_dir = xdg_config_home()?.join("ghp").join("profiles");
+    let mut bindings: Vec<DirBinding> = git_include_entries()?
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let dir = key.strip_prefix("includeif.gitdir:")?.strip_suffix(".path")?;
+            let fragment = Path::new(&value);
+            if fragment.parent() != Some(fragments_dir.as_path()) || fragment.extension()? != "gitconfig" {
+                return None;
+            }
+            let profile = fragment.file_stem()?.to_string_lossy().into_owned();
+            Some(DirBinding { dir: dir.to_string(), profile })
+        })
+        .collect();
+    bindings.sort_by(|a, b| a.dir.cmp(&b.dir));
+    Ok(bindings)
+}
+
+/// The `gitdir:` pattern for `dir`: absolute, with symlinks resolved and a trailing slash so that
+/// every repository below it matches.
+fn bind_pattern(dir: &str) -> Result<String> {
+    let path = expand_path(Path::new(dir))?;
+    let path = if path.is_absolute() { path } else { std::env::current_dir()?.join(path) };
+    let path = fs::canonicalize(&path).unwrap_or(path);
+    let pattern = path.display().to_string();
+    Ok(if pattern.ends_with('/') { pattern } else { format!("{}/", pattern) })
+}
+
+/// Removes the `includeIf` for a bound directory, dropping the whole section if ghp's entry was its only one.
+fn remove_dir_binding(binding: &DirBinding, includes: &[(String, String)]) -> Result<()> {
+    let section = format!("includeIf.gitdir:{}", binding.dir);
+    let prefix = format!("includeif.gitdir:{}.", binding.dir);
+    let key = format!("{}.path", section);
+    let fragment = profile_fragment_path(&binding.profile)?.display().to_string();
+    let edit = if includes.iter().filter(|(include, _)| include.starts_with(&prefix)).count() <= 1 {
+        GitConfigEdit::RemoveSection(&section)
+    } else {
+        GitConfigEdit::UnsetValue(&key, &fragment)
+    };
+    edit_git_config(&git_global_config_path()?, &[edit])
+}
+
+fn bind_dir(matches: &ArgMatches) -> Result<()> {
+    let profile_name = matches.get_one::<String>("profile")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let dir = matches.get_one::<String>("dir")
+        .ok_or_else(|| GhpError::MissingConfig("Directory required".to_string()))?;
+    let config = Config::load(&config_path(matches)?)?;
+    let profile = config.profiles.get(profile_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+    bind_profile_dir(profile_name, profile, &bind_pattern(dir)?)
+}
+
+/// Includes the profile's fragment for every repository matching `pattern`, replacing any other
+/// profile bound there.
+fn bind_profile_dir(profile_name: &str, profile: &Profile, pattern: &str) -> Result<()> {
+    let bindings = dir_bindings()?;
+    let includes = git_include_entries()?;
+    if let Some(existing) = bindings.iter().find(|binding| binding.dir == pattern) {
+        if existing.profile == profile_name {
+            println!("{} is already bound to profile '{}'", pattern, profile_name);
+            return Ok(());
+        }
+        remove_dir_binding(existing, &includes)?;
+        if !dry_run() {
+            println!("Unbound {} from profile '{}'", pattern, existing.profile);
+        }
+        prune_profile_fragment(&existing.profile, &bindings, pattern)?;
+    }
+
+    let fragment = profile_fragment_path(profile_name)?;
+    write_file(&fragment, render_profile_fragment(profile_name, profile)?)?;
+    set_git_global_config(&[(&format!("includeIf.gitdir:{}.path", pattern), &fragment.display().to_string())])?;
+    if !dry_run() {
+        println!("Repositories under {} now use profile '{}'", pattern, profile_name);
+    }
+    Ok(())
+}
+
+/// Deletes a profile's fragment once no directory other than `unbound` includes it.
+fn prune_profile_fragment(profile_name: &str, bindings: &[DirBinding], unbound: &str) -> Result<()> {
+    if !bindings.iter().any(|binding| binding.profile == profile_name && binding.dir != unbound) {
+        remove_file(&profile_fragment_path(profile_name)?)?;
+    }
+    Ok(())
+}
+
+fn unbind_dir(matches: &ArgMatches) -> Result<()> {
+    let dir = matches.get_one::<String>("dir")
+        .ok_or_else(|| GhpError::MissingConfig("Directory required".to_string()))?;
+    let pattern = bind_pattern(dir)?;
+    let bindings = dir_bindings()?;
+    let binding = bindings.iter()
+        .find(|binding| binding.dir == pattern)
+        .ok_or_else(|| GhpError::MissingConfig(format!("{} is not bound to a profile", pattern)))?;
+
+    remove_dir_binding(binding, &git_include_entries()?)?;
+    prune_profile_fragment(&binding.profile, &bindings, &pattern)?;
+    if !dry_run() {
+        println!("Unbound {} from profile '{}'", pattern, binding.profile);
+    }
+    Ok(())
+}
+
+/// Moves every directory bound to `old_name` over to `new_name`, or unbinds them if `new_name` is `None`.
+fn rebind_profile(old_name: &str, new: Option<(&str, &Profile)>) -> Result<()> {
+    let bindings: Vec<DirBinding> = dir_bindings()?
+        .into_iter()
+        .filter(|binding| binding.profile == old_name)
+        .collect();
+    if bindings.is_empty() {
+        return Ok(());
+    }
+    let includes = git_include_entries()?;
+    for binding in &bindings {
+        remove_dir_binding(binding, &includes)?;
+    }
+    remove_file(&profile_fragment_path(old_name)?)?;
+    match new {
+        Some((new_name, profile)) => {
+            let fragment = profile_fragment_path(new_name)?;
+            write_file(&fragment, render_profile_fragment(new_name, profile)?)?;
+            let fragment = fragment.display().to_string();
+            for binding in &bindings {
+                set_git_global_config(&[(&format!("includeIf.gitdir:{}.path", binding.dir), &fragment)])?;
+            }
+        }
+        None => {
+            if !dry_run() {
+                for binding in &bindings {
+                    println!("Unbound {} from profile '{}'", binding.dir, old_name);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Environment variables `ghp env` sets; all are cleared again when leaving a profile's directory.
+const PROFILE_ENV_VARS: &[&str] = &[
+    "GIT_AUTHOR_NAME",
+    "GIT_AUTHOR_EMAIL",
+    "GIT_COMMITTER_NAME",
+    "GIT_COMMITTER_EMAIL",
+    "GIT_SSH_COMMAND",
+    "GHP_PROFILE",
+];
+
+/// Environment variables that make git in the current directory act as the profile.
+fn profile_env(profile_name: &str, profile: &Profile) -> Result<Vec<(&'static str, String)>> {
+    Ok(vec![
+        ("GIT_AUTHOR_NAME", profile.username.clone()),
+        ("GIT_AUTHOR_EMAIL", profile.email.clone()),
+        ("GIT_COMMITTER_NAME", profile.username.clone()),
+        ("GIT_COMMITTER_EMAIL", profile.email.clone()),
+        ("GIT_SSH_COMMAND", ssh_command(&repo_binding(profile))?),
+        ("GHP_PROFILE", profile_name.to_string()),
+    ])
+}
+
+/// Runs a command as the profile, with git's identity, SSH key and signing settings passed through
+/// the environment, then exits with the command's status.
+fn exec_profile(matches: &ArgMatches) -> Result<()> {
+    let profile_name = matches.get_one::<String>("profile")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let mut command = matches.get_many::<String>("command")
+        .ok_or_else(|| GhpError::MissingConfig("Command required".to_string()))?;
+    let program = command.next()
+        .ok_or_else(|| GhpError::MissingConfig("Command required".to_string()))?;
+    let config = Config::load(&config_path(matches)?)?;
+    let profile = config.profiles.get(profile_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+
+    if dry_run() {
+        println!("Would run '{}' as profile '{}'", program, profile_name);
+        return Ok(());
+    }
+
+    let mut child = std::process::Command::new(program);
+    child.args(command).envs(profile_env(profile_name, profile)?);
+    // Per-invocation config entries go after any the caller already passes in the environment.
+    let offset: usize = std::env::var("GIT_CONFIG_COUNT").ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+    let signing = signing_config(profile);
+    for (index, (key, value)) in signing.iter().enumerate() {
+        child.env(format!("GIT_CONFIG_KEY_{}", offset + index), key);
+        child.env(format!("GIT_CONFIG_VALUE_{}", offset + index), value);
+    }
+    child.env("GIT_CONFIG_COUNT", (offset + signing.len()).to_string());
+
+    let status = child.status().map_err(|err| GhpError::CommandFailed(program.clone(), err))?;
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        std::process::exit(128 + signal);
+    }
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+/// The profile's signing settings, as layered over the user's own git config.
+fn signing_config(profile: &Profile) -> Vec<(&'static str, String)> {
+    layered_git_identity(profile)
+        .into_iter()
+        .filter(|(key, _)| SIGNING_GIT_KEYS.contains(key))
+        .collect()
+}
+
+/// The profile for the working directory: named by the nearest `.ghp-profile` file, or else by
+/// the most specific directory bound with `ghp bind`.
+fn directory_profile() -> Result<Option<String>> {
+    let cwd = std::env::current_dir()?;
+    for dir in cwd.ancestors() {
+        if let Ok(contents) = fs::read_to_string(dir.join(".ghp-profile")) {
+            let name = contents.lines().next().unwrap_or_default().trim();
+            if !name.is_empty() {
+                return Ok(Some(name.to_string()));
+            }
+        }
+    }
+    let cwd = format!("{}/", fs::canonicalize(&cwd).unwrap_or(cwd).display());
+    Ok(dir_bindings()?
+        .into_iter()
+        .filter(|binding| cwd.starts_with(&binding.dir))
+        .max_by_key(|binding| binding.dir.len())
+        .map(|binding| binding.profile))
+}
+
+fn print_env(matches: &ArgMatches) -> Result<()> {
+    let shell = matches.get_one::<String>("shell").map_or("bash", String::as_str);
+    let config = Config::load(&config_path(matches)?)?;
+    let profile_name = match matches.get_one::<String>("profile") {
+        Some(profile_name) => Some(profile_name.clone()),
+        None => directory_profile()?,
+    };
+
+    match profile_name {
+        Some(profile_name) => {
+            let profile = config.profiles.get(&profile_name)
+                .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+            for (name, value) in profile_env(&profile_name, profile)? {
+                match shell {
+                    "fish" => println!("set -gx {} {}", name, fish_quote(&value)),
+                    _ => println!("export {}={}", name, shell_quote(&value)),
+                }
+            }
+        }
+        // Only undo what an earlier `ghp env` set, never variables the user exported themselves.
+        None if std::env::var_os("GHP_PROFILE").is_some() => match shell {
+            "fish" => println!("set -e {}", PROFILE_ENV_VARS.join(" ")),
+            _ => println!("unset {}", PROFILE_ENV_VARS.join(" ")),
+        },
+        None => {}
+    }
+    Ok(())
+}
+
+/// Quotes `value` for fish, where only `\` and `'` are special inside single quotes.
+fn fish_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn print_hook(matches: &ArgMatches) -> Result<()> {
+    let shell = matches.get_one::<String>("shell")
+        .ok_or_else(|| GhpError::MissingConfig("Shell required".to_string()))?;
+    let ghp = std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| "ghp".to_string());
+
+    let hook = match shell.as_str() {
+        "bash" => format!(
+            r#"_ghp_hook() {{
+  if [ "$PWD" != "${{_GHP_PWD-}}" ]; then
+    _GHP_PWD=$PWD
+    eval "$({ghp} env --shell bash)"
+  fi
+}}
+case ";${{PROMPT_COMMAND-}};" in
+  *";_ghp_hook;"*) ;;
+  *) PROMPT_COMMAND="_ghp_hook${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}" ;;
+esac
+"#,
+            ghp = shell_quote(&ghp)
+        ),
+        "zsh" => format!(
+            r#"_ghp_hook() {{
+  eval "$({ghp} env --shell zsh)"
+}}
+autoload -Uz add-zsh-hook
+add-zsh-hook chpwd _ghp_hook
+_ghp_hook
+"#,
+            ghp = shell_quote(&ghp)
+        ),
+        _ => format!(
+            r#"function __ghp_hook --on-variable PWD
+    {ghp} env --shell fish | source
+end
+__ghp_hook
+"#,
+            ghp = fish_quote(&ghp)
+        ),
+    };
+    print!("{}", hook);
+    Ok(())
+}
+
+fn list_bindings(matches: &ArgMatches) -> Result<()> {
+    let config = Config::load(&config_path(matches)?)?;
+    let bindings = dir_bindings()?;
+    if bindings.is_empty() {
+        println!("No directories are bound. Bind one with `ghp bind <profile> <dir>`.");
+        return Ok(());
+    }
+    for binding in &bindings {
+        let missing = if config.profiles.contains_key(&binding.profile) { "" } else { " (profile not found)" };
+        println!("{} -> {}{}", binding.dir, binding.profile, missing);
+    }
+    Ok(())
+}
+
+/// Clones a repository through the profile's SSH alias and ties the clone to the profile.
+fn clone_repo(matches: &ArgMatches) -> Result<()> {
+    let profile_name = matches.get_one::<String>("profile")
+        .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
+    let url = matches.get_one::<String>("url")
+        .ok_or_else(|| GhpError::MissingConfig("Repository URL required".to_string()))?;
+    let config = Config::load(&config_path(matches)?)?;
+    let profile = config.profiles.get(profile_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+
+    let (alias_url, binding) = profile_clone_url(url, profile_name, profile)?;
+    let dir = match matches.get_one::<String>("dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(clone_dir_name(&alias_url)
+            .ok_or_else(|| GhpError::MissingConfig(format!("cannot guess a directory name from '{}'", url)))?),
+    };
+
+    if dry_run() {
+        println!("Would clone {} into {}", alias_url, dir.display());
+    } else {
+        println!("Cloning {} into {}", alias_url, dir.display());
+        let status = std::process::Command::new("git")
+            .arg("clone")
+            .arg(&alias_url)
+            .arg(&dir)
+            .status()
+            .map_err(|err| GhpError::CommandFailed("git".to_string(), err))?;
+        if !status.success() {
+            return Err(GhpError::CloneFailed(alias_url));
+        }
+        write_local_identity(&dir.join(".git").join("config"), profile, &binding)?;
+    }
+
+    if matches.get_flag("bind") {
+        bind_profile_dir(profile_name, profile, &bind_pattern(&dir.display().to_string())?)?;
+    }
+    if !dry_run() {
+        println!("Cloned as profile '{}'", profile_name);
+    }
+    Ok(())
+}
+
+/// Rewrites an SSH, HTTPS or `owner/repo` URL to go through the profile's `Host <forge>-<profile>`
+/// alias, returning it with the binding for that forge.
+fn profile_clone_url(url: &str, profile_name: &str, profile: &Profile) -> Result<(String, HostBinding)> {
+    let bindings = profile.bindings();
+    let find_binding = |host: &str| {
+        bindings.iter()
+            .find(|binding| binding.host.eq_ignore_ascii_case(host) || binding.alias(profile_name).eq_ignore_ascii_case(host))
+            .cloned()
+            .ok_or_else(|| GhpError::MissingConfig(format!(
+                "profile '{}' is not bound to host '{}'; add it with `ghp host add {} {} --ssh-key <key>`",
+                profile_name, host, profile_name, host
+            )))
+    };
+    let invalid = || GhpError::MissingConfig(format!("unsupported repository URL '{}'", url));
+
+    if let Some(rest) = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")) {
+        let (authority, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+        let host = host.split_once(':').map_or(host, |(host, _)| host);
+        let binding = find_binding(host)?;
+        let path = https_repo_path(path).ok_or_else(invalid)?;
+        return Ok((format!("{}@{}:{}.git", binding.ssh_user(), binding.alias(profile_name), path), binding));
+    }
+    if let Some(rest) = url.strip_prefix("ssh://") {
+        let (authority, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (format!("{}@", user), host_port),
+            None => (String::new(), authority),
+        };
+        // Any port is dropped: the alias block carries the binding's own `Port`.
+        let host = host_port.split_once(':').map_or(host_port, |(host, _)| host);
+        let binding = find_binding(host)?;
+        return Ok((format!("ssh://{}{}/{}", user, binding.alias(profile_name), path), binding));
+    }
+    if url.contains("://") {
+        return Err(invalid());
+    }
+    if let Some((authority, path)) = url.split_once(':') {
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (user.to_string(), host),
+            None => (String::new(), authority),
+        };
+        let binding = find_binding(host)?;
+        let user = if user.is_empty() { binding.ssh_user().to_string() } else { user };
+        return Ok((format!("{}@{}:{}", user, binding.alias(profile_name), path), binding));
+    }
+    // `owner/repo` on the profile's primary forge.
+    let (owner, repo) = url.split_once('/').ok_or_else(invalid)?;
+    if owner.is_empty() || repo.is_empty() || repo.contains('/') {
+        return Err(invalid());
+    }
+    let binding = bindings[0].clone();
+    let repo = repo.strip_suffix(".git").unwrap_or(repo);
+    Ok((format!("{}@{}:{}/{}.git", binding.ssh_user(), binding.alias(profile_name), owner, repo), binding))
+}
+
+/// The `owner/repo` part of an HTTPS URL path, without `.git`, so that links copied from the
+/// browser (`org/repo/tree/main`, `group/sub/repo/-/blob/main/README.md`) name the repository.
+fn https_repo_path(path: &str) -> Option<String> {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    // GitLab puts every page below a `-` segment; GitHub and Bitbucket never nest repositories.
+    let end = segments.iter().enumerate()
+        .position(|(index, segment)| index >= 2 && (*segment == "-" || (index == 2 && WEB_PAGES.contains(segment))))
+        .unwrap_or(segments.len());
+    if end < 2 {
+        return None;
+    }
+    let path = segments[..end].join("/");
+    Some(path.strip_suffix(".git").map(str::to_string).unwrap_or(path))
+}
+
+/// Pages GitHub and Bitbucket serve right below `owner/repo`.
+const WEB_PAGES: &[&str] = &[
+    "tree", "blob", "commit", "commits", "pull", "pulls", "issues", "releases", "tags", "branches", "actions", "wiki",
+    "compare", "src",
+];
+
+/// The directory `git clone` creates for `url` when none is given.
+fn clone_dir_name(url: &str) -> Option<String> {
+    let path = url.trim_end_matches('/');
+    let name = path.rsplit(['/', ':']).next()?;
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    Some(name.to_string()).filter(|name| !name.is_empty())
+}
+
+fn find_host_identity(ssh_config: &SshConfig, host: &str) -> Option<PathBuf> {
+    let block = ssh_config.find_host(host)?;
+    ssh_config.get(&block, "IdentityFile").map(PathBuf::from)
+}
+
+fn alias_host(host: &str, profile_name: &str) -> String {
+    format!("{}-{}", host, profile_name)
+}
+
+/// Writes an alias block for every host `profile` is bound to.
+fn set_alias_blocks(ssh_config: &mut SshConfig, profile_name: &str, profile: &Profile) -> Result<()> {
+    for binding in profile.bindings() {
+        set_host_block(ssh_config, &binding.alias(profile_name), &binding, &[])?;
+    }
+    Ok(())
+}
+
+fn remove_alias_blocks(ssh_config: &mut SshConfig, profile_name: &str, profile: &Profile) {
+    for binding in profile.bindings() {
+        ssh_config.remove_host(&binding.alias(profile_name));
+    }
+}
+
+/// Writes a binding's connection settings and SSH options into the block for `host`, editing it
+/// in place (and keeping any other directives) or appending a new one. Keywords in `stale` that
+/// the binding does not set are removed, so options of a previously active profile do not linger.
+fn set_host_block(ssh_config: &mut SshConfig, host: &str, binding: &HostBinding, stale: &[String]) -> Result<()> {
+    let ssh_key = binding.ssh_key_path()?.display().to_string();
+    let port = binding.ssh_port.map(|port| port.to_string());
+    let option = |keyword: &str| {
+        binding.ssh_options.iter()
+            .find(|(option, _)| option.eq_ignore_ascii_case(keyword))
+            .map(|(_, value)| value.as_str())
+    };
+    let mut directives = vec![
+        ("HostName", option("HostName").unwrap_or(&binding.host)),
+        ("User", binding.ssh_user()),
+    ];
+    if let Some(port) = &port {
+        directives.push(("Port", port));
+    }
+    directives.push(("IdentityFile", &ssh_key));
+    // Without this, ssh offers every key in the agent first and may authenticate as the wrong account.
+    directives.push(("IdentitiesOnly", option("IdentitiesOnly").unwrap_or("yes")));
+    let is_base = |keyword: &str| directives.iter().any(|(base, _)| base.eq_ignore_ascii_case(keyword));
+
+    if ssh_config.find_host(host).is_none() {
+        ssh_config.append_host(host, &directives);
+    } else {
+        for (keyword, value) in &directives {
+            if let Some(block) = ssh_config.find_host(host) {
+                ssh_config.set(&block, keyword, value);
+            }
+        }
+        if let (None, Some(block)) = (&port, ssh_config.find_host(host)) {
+            ssh_config.unset(&block, "Port");
+        }
+    }
+    // Options are written as is, since some (e.g. `ProxyCommand`) take several arguments.
+    for (keyword, value) in binding.ssh_options.iter().filter(|(keyword, _)| !is_base(keyword)) {
+        if let Some(block) = ssh_config.find_host(host) {
+            ssh_config.set_raw(&block, keyword, value);
+        }
+    }
+    for keyword in stale.iter().filter(|keyword| option(keyword).is_none() && !is_base(keyword)) {
+        if let Some(block) = ssh_config.find_host(host) {
+            ssh_config.unset(&block, keyword);
+        }
+    }
+    Ok(())
+}
+
+/// Every SSH option keyword used by any profile, for clearing them from the canonical host blocks.
+fn ssh_option_keywords(config: &Config) -> Vec<String> {
+    let mut keywords: Vec<String> = config.profiles.values()
+        .flat_map(|profile| profile.bindings())
+        .flat_map(|binding| binding.ssh_options)
+        .map(|(keyword, _)| keyword)
+        .collect();
+    keywords.sort_by_key(|keyword| keyword.to_ascii_lowercase());
+    keywords.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+    keywords
+}
+
+fn read_ssh_config(path: &Path) -> SshConfig {
+    SshConfig::parse(&read_file(path).unwrap_or_default())
+}
+
+/// Applies `edit` to ghp's managed section of the SSH config, leaving everything outside it untouched.
+fn update_managed_ssh_config(path: &Path, edit: impl FnOnce(&mut SshConfig) -> Result<()>) -> Result<SshConfig> {
+    let original = read_file(path).unwrap_or_default();
+    let mut ssh_config = SshConfig::parse(&original);
+    let mut managed = ssh_config.managed();
+    edit(&mut managed)?;
+    ssh_config.set_managed(managed);
+
+    let updated = ssh_config.to_string();
+    if updated != original {
+        write_file(path, updated)?;
+    }
+    Ok(ssh_config)
+}
+
+/// How deeply `Include` directives are followed, as in OpenSSH.
+const MAX_INCLUDE_DEPTH: usize = 16;
+/// Keywords that may be given several times, each adding to the previous values.
+const CUMULATIVE_KEYWORDS: &[&str] = &[
+    "IdentityFile", "CertificateFile", "LocalForward", "RemoteForward", "DynamicForward", "SendEnv", "Include",
+];
+
+/// The SSH config and every file it pulls in with `Include`, each parsed once.
+fn load_ssh_config_files(path: &Path) -> Vec<(PathBuf, SshConfig)> {
+    let mut files = Vec::new();
+    collect_ssh_config_files(path, 0, &mut files);
+    files
+}
+
+fn collect_ssh_config_files(path: &Path, depth: usize, files: &mut Vec<(PathBuf, SshConfig)>) {
+    if depth > MAX_INCLUDE_DEPTH || files.iter().any(|(seen, _)| seen == path) {
+        return;
+    }
+    let ssh_config = read_ssh_config(path);
+    let includes: Vec<PathBuf> = ssh_config.blocks().iter()
+        .flat_map(|block| ssh_config.includes(block))
+        .flat_map(|pattern| ssh_include_paths(&pattern))
+        .collect();
+    files.push((path.to_path_buf(), ssh_config));
+    for include in includes {
+        collect_ssh_config_files(&include, depth + 1, files);
+    }
+}
+
+fn ssh_include_paths(pattern: &str) -> Vec<PathBuf> {
+    let ssh_dir = home_dir().map(|home| home.join(".ssh")).unwrap_or_default();
+    let pattern = expand_path(Path::new(pattern)).unwrap_or_else(|_| PathBuf::from(pattern));
+    ssh_config::include_paths(&pattern, &ssh_dir)
+}
+
+/// A block of one of the files from `load_ssh_config_files`.
+struct HostBlock {
+    file: usize,
+    block: ssh_config::Block,
+}
+
+/// Every block of the SSH config, with included files spliced in where SSH reads them.
+fn ssh_host_blocks(files: &[(PathBuf, SshConfig)]) -> Vec<HostBlock> {
+    let mut blocks = Vec::new();
+    if !files.is_empty() {
+        walk_ssh_config(files, 0, 0, &mut blocks);
+    }
+    blocks
+}
+
+fn walk_ssh_config(files: &[(PathBuf, SshConfig)], file: usize, depth: usize, blocks: &mut Vec<HostBlock>) {
+    if depth > MAX_INCLUDE_DEPTH {
+        return;
+    }
+    let ssh_config = &files[file].1;
+    for block in ssh_config.blocks() {
+        let includes = ssh_config.includes(&block);
+        blocks.push(HostBlock { file, block });
+        for include in includes.iter().flat_map(|pattern| ssh_include_paths(pattern)) {
+            if let Some(index) = files.iter().position(|(path, _)| *path == include) {
+                walk_ssh_config(files, index, depth + 1, blocks);
+            }
+        }
+    }
+}
+
+fn host_location(files: &[(PathBuf, SshConfig)], file: usize, line: usize) -> String {
+    format!("{}:{}", files[file].0.display(), line)
+}
+
+fn dedupe_ssh_hosts(matches: &ArgMatches) -> Result<()> {
+    let config = Config::load(&config_path(matches)?)?;
+    let mut files = load_ssh_config_files(&config.ssh_config_path);
+    let blocks = ssh_host_blocks(&files);
+
+    // Hosts named literally by more than one block, in the order SSH first meets them.
+    let mut occurrences: Vec<(String, Vec<usize>)> = Vec::new();
+    for (index, host_block) in blocks.iter().enumerate() {
+        let ssh_config::Section::Host(patterns) = &host_block.block.section else {
+            continue;
+        };
+        for pattern in patterns.iter().filter(|pattern| !pattern.starts_with('!') && !ssh_config::is_wildcard(pattern)) {
+            match occurrences.iter_mut().find(|(host, _)| host.eq_ignore_ascii_case(pattern)) {
+                Some((_, indices)) if !indices.contains(&index) => indices.push(index),
+                Some(_) => {}
+                None => occurrences.push((pattern.clone(), vec![index])),
+            }
+        }
+    }
+    let duplicates: Vec<&(String, Vec<usize>)> = occurrences.iter().filter(|(_, indices)| indices.len() > 1).collect();
+    for (host, indices) in &duplicates {
+        println!("'Host {}' is defined {} times:", host, indices.len());
+        for &index in indices {
+            println!("  {}", host_location(&files, blocks[index].file, blocks[index].block.line()));
+        }
+    }
+
+    // Settings that never take effect because an earlier matching block already set them.
+    let mut shadowed = Vec::new();
+    for (host, indices) in &occurrences {
+        for &index in indices {
+            let host_block = &blocks[index];
+            for entry in files[host_block.file].1.entries(&host_block.block) {
+                if CUMULATIVE_KEYWORDS.iter().any(|keyword| keyword.eq_ignore_ascii_case(&entry.keyword)) {
+                    continue;
+                }
+                let earlier = blocks[..index].iter().find_map(|earlier| {
+                    let applies = match &earlier.block.section {
+                        ssh_config::Section::Global => earlier.file == 0,
+                        ssh_config::Section::Host(patterns) => ssh_config::matches_host(patterns, host),
+                        ssh_config::Section::Match(criteria) => criteria.len() == 1 && criteria[0].eq_ignore_ascii_case("all"),
+                    };
+                    if !applies {
+                        return None;
+                    }
+                    files[earlier.file].1.entries(&earlier.block).into_iter()
+                        .find(|other| other.keyword.eq_ignore_ascii_case(&entry.keyword))
+                        .map(|other| host_location(&files, earlier.file, other.line))
+                });
+                let location = host_location(&files, host_block.file, entry.line);
+                if let Some(earlier) = earlier {
+                    if !shadowed.iter().any(|(seen, _, _, _)| *seen == location) {
+                        shadowed.push((location, entry.keyword, host.clone(), earlier));
+                    }
+                }
+            }
+        }
+    }
+    for (location, keyword, host, earlier) in &shadowed {
+        println!("{}: '{}' has no effect for {}; it is already set at {}", location, keyword, host, earlier);
+    }
+
+    if duplicates.is_empty() {
+        if shadowed.is_empty() {
+            println!("No duplicate or shadowed Host entries found");
+        }
+        return Ok(());
+    }
+    let confirmed = matches.get_flag("yes") || dry_run() || (io::stdin().is_terminal() && {
+        let answer = read_input(&format!("Consolidate {} duplicated host(s)? [y/N] ", duplicates.len()))?;
+        answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+    });
+    if !confirmed {
+        println!("Nothing changed; run `ghp ssh dedupe --yes` to consolidate them.");
+        return Ok(());
+    }
+
+    let originals: Vec<String> = files.iter().map(|(_, ssh_config)| ssh_config.to_string()).collect();
+    let hosts: Vec<String> = duplicates.iter().map(|(host, _)| host.clone()).collect();
+    for host in &hosts {
+        let binding = config.profiles.iter()
+            .flat_map(|(name, profile)| profile.bindings().into_iter().map(move |binding| (name, binding)))
+            .find(|(name, binding)| binding.alias(name).eq_ignore_ascii_case(host));
+        let ssh_key = match binding {
+            Some((_, binding)) => Some(binding.ssh_key_path()?),
+            None => None,
+        };
+        consolidate_ssh_host(&mut files, host, ssh_key.as_deref());
+    }
+    for ((path, ssh_config), original) in files.iter().zip(&originals) {
+        let updated = ssh_config.to_string();
+        if updated != *original {
+            write_file(path, updated)?;
+        }
+    }
+    Ok(())
+}
+
+/// Merges every block naming `host` into one: the block in ghp's managed section if there is one,
+/// otherwise the first. Settings only the other blocks had are carried over, then `host` is removed
+/// from them. For a profile alias, the profile's key replaces whatever `IdentityFile` was there.
+fn consolidate_ssh_host(files: &mut [(PathBuf, SshConfig)], host: &str, ssh_key: Option<&Path>) {
+    let blocks: Vec<HostBlock> = ssh_host_blocks(files).into_iter()
+        .filter(|host_block| host_block.block.names(host))
+        .collect();
+    let Some(target_index) = blocks.iter()
+        .position(|host_block| files[host_block.file].1.is_managed(&host_block.block))
+        .or((!blocks.is_empty()).then_some(0))
+    else {
+        return;
+    };
+    let target = &blocks[target_index];
+    let target_location = host_location(files, target.file, target.block.line());
+    if matches!(&target.block.section, ssh_config::Section::Host(patterns) if patterns.len() > 1) {
+        println!("Skipped 'Host {}': {} also applies to other hosts; consolidate it by hand", host, target_location);
+        return;
+    }
+
+    let mut keywords: Vec<String> = files[target.file].1.entries(&target.block).into_iter()
+        .map(|entry| entry.keyword.to_ascii_lowercase())
+        .collect();
+    let mut carried = Vec::new();
+    let mut others: Vec<&HostBlock> = blocks.iter()
+        .enumerate()
+        .filter(|(index, _)| *index != target_index)
+        .map(|(_, host_block)| host_block)
+        .collect();
+    for other in &others {
+        for entry in files[other.file].1.entries(&other.block) {
+            let keyword = entry.keyword.to_ascii_lowercase();
+            if keyword != "include" && !keywords.contains(&keyword) {
+                keywords.push(keyword);
+                carried.push(entry);
+            }
+        }
+    }
+
+    // Later lines first, so that removing a block does not shift the others in the same file.
+    others.sort_by_key(|host_block| std::cmp::Reverse((host_block.file, host_block.block.line())));
+    for other in &others {
+        files[other.file].1.remove_patterns(&other.block, |pattern| pattern.eq_ignore_ascii_case(host));
+    }
+
+    let ssh_config = &mut files[target.file].1;
+    for entry in carried {
+        if let Some(block) = ssh_config.find_host(host) {
+            ssh_config.set_raw(&block, &entry.keyword, &entry.value);
+        }
+    }
+    if let (Some(ssh_key), Some(block)) = (ssh_key, ssh_config.find_host(host)) {
+        ssh_config.set(&block, "IdentityFile", &ssh_key.display().to_string());
+    }
+    if !dry_run() {
+        println!("Consolidated 'Host {}' into {}", host, target_location);
+    }
+}
+
+/// Whether ghp manages `host`: the canonical host of a profile's forge (or `github.com`), or a
+/// `<forge>-<profile>` alias of one.
+fn is_managed_host(config: &Config, host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    config.profiles.values()
+        .flat_map(|profile| profile.bindings())
+        .map(|binding| binding.host.to_ascii_lowercase())
+        .chain([DEFAULT_HOST.to_string()])
+        .any(|forge| host == forge || host.strip_prefix(&forge).is_some_and(|rest| rest.starts_with('-')))
+}
+
+fn adopt_ssh_hosts(matches: &ArgMatches) -> Result<()> {
+    let config = Config::load(&config_path(matches)?)?;
+    let mut ssh_config = read_ssh_config(&config.ssh_config_path);
+    let mut managed = ssh_config.managed();
+
+    let blocks: Vec<_> = ssh_config.blocks().into_iter()
+        .filter(|block| !ssh_config.is_managed(block))
+        .collect();
+    let mut adopted = Vec::new();
+    let mut skipped = Vec::new();
+    for block in &blocks {
+        let ssh_config::Section::Host(patterns) = &block.section else {
+            continue;
+        };
+        for host in patterns.iter().filter(|pattern| is_managed_host(&config, pattern)) {
+            if managed.find_host(host).is_some() {
+                skipped.push((host.clone(), block.line()));
+            } else {
+                managed.append_copy(&ssh_config, block, host);
+                adopted.push((host.clone(), block.line()));
+            }
+        }
+    }
+    if adopted.is_empty() && skipped.is_empty() {
+        println!("No unmanaged forge host blocks found in {}", config.ssh_config_path.display());
+        return Ok(());
+    }
+
+    // Later blocks first, so that removing one does not shift the lines of the rest.
+    let adopted_hosts: Vec<&str> = adopted.iter().map(|(host, _)| host.as_str()).collect();
+    for block in blocks.iter().rev() {
+        ssh_config.remove_patterns(block, |pattern| adopted_hosts.contains(&pattern));
+    }
+    ssh_config.set_managed(managed);
+    write_file(&config.ssh_config_path, ssh_config.to_string())?;
+
+    if !dry_run() {
+        for (host, line) in &adopted {
+            println!("Adopted 'Host {}' from line {}", host, line);
+        }
+    }
+    for (host, line) in &skipped {
+        println!("Skipped 'Host {}' on line {}: ghp already manages that host, so merge or delete it by hand", host, line);
+    }
+    Ok(())
+}
+
+fn remove_profile(matches: &ArgMatches) -> Result<()> {
+    let profile_name = matches.get_one::<String>("profile")
         .ok_or_else(|| GhpError::MissingConfig("Profile name required".to_string()))?;
-    let mut config = Config::load()?;
+    let mut config = Config::load(&config_path(matches)?)?;
+
+    let was_active = ActiveIdentity::detect(&config)?
+        .ssh_profile(&config) == Some(profile_name.as_str());
+    let profile = config.profiles.remove(profile_name)
+        .ok_or_else(|| GhpError::ProfileNotFound(profile_name.clone()))?;
+
+    if !matches.get_flag("keep_ssh") {
+        update_managed_ssh_config(&config.ssh_config_path, |managed| {
+            remove_alias_blocks(managed, profile_name, &profile);
+            if was_active {
+                for binding in profile.bindings() {
+                    managed.remove_host(&binding.host);
+                }
+            }
+            Ok(())
+        })?;
+    }
+    rebind_profile(profile_name, None)?;
 
-    if config.profiles.remove(profile_name).is_some() {
-        config.save()?;
+    config.save()?;
+    if !dry_run() {
         println!("Profile '{}' removed successfully!", profile_name);
-        Ok(())
+    }
+    if was_active {
+        println!("Warning: '{}' was the active profile; git user.name and user.email still point to it.", profile_name);
+        match config.profiles.keys().next() {
+            Some(other) => println!("Run `ghp switch <profile>` (e.g. `ghp switch {}`) to activate another profile.", other),
+            None => println!("Run `ghp add <profile>` to create a new profile."),
+        }
+    }
+    Ok(())
+}
+
+fn list_profiles(matches: &ArgMatches) -> Result<()> {
+    let config = Config::load(&config_path(matches)?)?;
+    if config.profiles.is_empty() {
+        println!("No profiles configured. Add one with `ghp add <profile>`.");
+        return Ok(());
+    }
+
+    let identity = ActiveIdentity::detect(&config)?;
+    let active = identity.active_profile(&config);
+
+    for (name, profile) in &config.profiles {
+        let marker = if Some(name.as_str()) == active { "*" } else { " " };
+        println!("{} {}", marker, name);
+        println!("    username: {}", profile.username);
+        println!("    email:    {}", profile.email);
+        println!("    ssh_key:  {}", profile.ssh_key.display());
+        if profile.host != DEFAULT_HOST || profile.ssh_port.is_some() || profile.ssh_user.is_some() {
+            let port = profile.ssh_port.map(|port| format!(":{}", port)).unwrap_or_default();
+            println!("    host:     {}@{}{}", profile.ssh_user(), profile.host, port);
+        }
+        if !profile.ssh_options.is_empty() {
+            println!("    options:  {}", format_ssh_options(&profile.ssh_options));
+        }
+        if let Some(signing_key) = &profile.signing_key {
+            println!("    signing:  {} ({})", signing_key, profile.signing_format.as_deref().unwrap_or(DEFAULT_SIGNING_FORMAT));
+        }
+        for binding in &profile.hosts {
+            let port = binding.ssh_port.map(|port| format!(":{}", port)).unwrap_or_default();
+            println!("    also:     {}@{}{} with {}", binding.ssh_user(), binding.host, port, binding.ssh_key.display());
+            if !binding.ssh_options.is_empty() {
+                println!("              {}", format_ssh_options(&binding.ssh_options));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn format_ssh_options(options: &[(String, String)]) -> String {
+    options.iter()
+        .map(|(keyword, value)| format!("{} {}", keyword, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn current_profile(matches: &ArgMatches) -> Result<()> {
+    let config = Config::load(&config_path(matches)?)?;
+    let identity = ActiveIdentity::detect(&config)?;
+    let ssh_profile = identity.ssh_profile(&config);
+    let git_profile = identity.git_profile(&config);
+
+    for (host, ssh_key) in &identity.ssh_keys {
+        match ssh_key {
+            Some(ssh_key) => println!("SSH {}: {} ({})", host, ssh_key.display(), describe_profile(identity.host_profile(&config, host))),
+            None => println!("SSH {}: no IdentityFile for Host {}", host, host),
+        }
+    }
+    match (&identity.git_username, &identity.git_email) {
+        (None, None) => println!("git: user.name and user.email are not set"),
+        (username, email) => println!("git: {} <{}> ({})",
+            username.as_deref().unwrap_or("<unset>"),
+            email.as_deref().unwrap_or("<unset>"),
+            describe_profile(git_profile)),
+    }
+
+    match (ssh_profile, git_profile) {
+        (Some(ssh), Some(git)) if ssh == git => {
+            println!("Current profile: {}", ssh);
+            Ok(())
+        }
+        _ => Err(GhpError::IdentityMismatch(format!(
+            "SSH says {}, git says {}",
+            describe_profile(ssh_profile),
+            describe_profile(git_profile)
+        ))),
+    }
+}
+
+fn describe_profile(profile: Option<&str>) -> String {
+    match profile {
+        Some(name) => format!("'{}'", name),
+        None => "no known profile".to_string(),
+    }
+}
+
+fn check_config(matches: &ArgMatches) -> Result<()> {
+    // Parse strictly from the start so every problem is reported at once,
+    // following a `setup -g` redirect by hand instead of through `Config::resolve`.
+    let path = config_path(matches)?;
+    let mut config = check_config_file(&path)?;
+    let path = match config.redirect_target(&path)? {
+        Some(target) => {
+            config = check_config_file(&target)?;
+            target
+        }
+        None => path,
+    };
+    println!("{}: OK ({} profiles)", path.display(), config.profiles.len());
+    Ok(())
+}
+
+fn check_config_file(path: &Path) -> Result<Config> {
+    let content = fs::read_to_string(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => GhpError::MissingConfig(format!(
+            "{} does not exist; run `ghp setup` first",
+            path.display()
+        )),
+        _ => err.into(),
+    })?;
+
+    if Config::is_legacy(&content) {
+        println!("{} uses the legacy format and will be migrated on next use.", path.display());
+        Config::parse_legacy_config(&content, path, true)
     } else {
-        Err(GhpError::ProfileNotFound(profile_name.clone()))
+        Config::parse_config(&content, path, true)
+    }
+}
+
+fn list_backups() -> Result<()> {
+    let snapshots = backup::list()?;
+    if snapshots.is_empty() {
+        println!("No backups yet.");
+        return Ok(());
+    }
+    for snapshot in snapshots.iter().rev() {
+        println!("{}  {}  {}", snapshot.id, backup::format_timestamp(snapshot.created, false), snapshot.command);
+    }
+    Ok(())
+}
+
+fn undo(matches: &ArgMatches) -> Result<()> {
+    let _lock = ConfigLock::acquire(&Config::resolve(&config_path(matches)?)?)?;
+    let snapshot = backup::find(matches.get_one::<String>("id").map(String::as_str))?;
+
+    if dry_run() {
+        snapshot.restore()?;
+        print_restored(&snapshot);
+        return Ok(());
+    }
+
+    // Back up the current state too, so that the undo can itself be undone.
+    let paths: Vec<PathBuf> = snapshot.paths().map(Path::to_path_buf).collect();
+    let current = backup::Snapshot::create(&paths, &format!("ghp undo {}", snapshot.id))?;
+    let result = snapshot.restore();
+    if result.is_ok() {
+        print_restored(&snapshot);
+        snapshot.delete()?;
+    }
+    current.finish(result.is_ok())?;
+    result
+}
+
+fn print_restored(snapshot: &backup::Snapshot) {
+    let verb = if dry_run() { "Would restore" } else { "Restored" };
+    println!("{} backup {} taken before `{}`:", verb, snapshot.id, snapshot.command);
+    for path in snapshot.paths() {
+        println!("  {}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn home() -> String {
+        std::env::var("HOME").expect("tests need HOME")
+    }
+
+    fn expand(path: &str) -> Result<String> {
+        expand_path(Path::new(path)).map(|path| path.display().to_string())
+    }
+
+    #[test]
+    fn expands_home() {
+        let home = home();
+        assert_eq!(expand("~").unwrap(), home);
+        assert_eq!(expand("~/.ssh/id").unwrap(), format!("{}/.ssh/id", home));
+        assert_eq!(expand("~user/.ssh/id").unwrap(), "~user/.ssh/id");
+        assert_eq!(expand("/a/~/b").unwrap(), "/a/~/b");
+        assert_eq!(expand("relative/path").unwrap(), "relative/path");
+    }
+
+    #[test]
+    fn expands_variables() {
+        let home = home();
+        assert_eq!(expand("$HOME/.ssh").unwrap(), format!("{}/.ssh", home));
+        assert_eq!(expand("${HOME}x/$HOME").unwrap(), format!("{}x/{}", home, home));
+        assert_eq!(expand("/a/$HOME-b").unwrap(), format!("/a/{}-b", home));
+        assert!(matches!(
+            expand("$GHP_TEST_UNDEFINED_VARIABLE/x"),
+            Err(GhpError::UndefinedVariable(name)) if name == "GHP_TEST_UNDEFINED_VARIABLE"
+        ));
+        assert!(matches!(expand("${GHP_TEST_UNDEFINED_VARIABLE}"), Err(GhpError::UndefinedVariable(_))));
+    }
+
+    #[test]
+    fn keeps_dollars_that_start_no_variable() {
+        assert_eq!(expand("/a$/b").unwrap(), "/a$/b");
+        assert_eq!(expand("/a/b$").unwrap(), "/a/b$");
+        assert_eq!(expand("/a/${}/b").unwrap(), "/a/${}/b");
+        assert_eq!(expand("/a/${HOME/b").unwrap(), "/a/${HOME/b");
+        assert_eq!(expand("$$HOME").unwrap(), format!("${}", home()));
+    }
+
+    #[test]
+    fn contracts_home() {
+        let home = PathBuf::from(home());
+        assert_eq!(contract_path(&home), PathBuf::from("~"));
+        assert_eq!(contract_path(&home.join(".ssh/id")), PathBuf::from("~/.ssh/id"));
+        assert_eq!(contract_path(Path::new("/elsewhere/id")), PathBuf::from("/elsewhere/id"));
+        let sibling = PathBuf::from(format!("{}-other/id", home.display()));
+        assert_eq!(contract_path(&sibling), sibling);
+        assert_eq!(expand_path(&contract_path(&home.join("x"))).unwrap(), home.join("x"));
+    }
+
+    fn profile() -> Profile {
+        let mut profile = Profile::new("w".to_string(), "w@example.com".to_string(), PathBuf::from("/keys/id_work"));
+        profile.hosts.push(HostBinding {
+            host: "gitlab.com".to_string(),
+            ssh_key: PathBuf::from("/keys/id_gitlab"),
+            ssh_port: Some(2222),
+            ssh_user: None,
+            ssh_options: Vec::new(),
+        });
+        profile
+    }
+
+    fn clone_url(url: &str) -> Result<(String, String)> {
+        profile_clone_url(url, "work", &profile()).map(|(url, binding)| (url, binding.host))
+    }
+
+    #[test]
+    fn clone_url_rewrites_scp_style() {
+        assert_eq!(clone_url("git@github.com:org/repo.git").unwrap(), ("git@github.com-work:org/repo.git".to_string(), "github.com".to_string()));
+        assert_eq!(clone_url("gitlab.com:group/repo").unwrap().0, "git@gitlab.com-work:group/repo");
+        assert_eq!(clone_url("me@github.com-work:org/repo.git").unwrap().0, "me@github.com-work:org/repo.git");
+    }
+
+    #[test]
+    fn clone_url_rewrites_ssh_and_drops_the_port() {
+        assert_eq!(clone_url("ssh://git@gitlab.com:2222/group/repo.git").unwrap(), ("ssh://git@gitlab.com-work/group/repo.git".to_string(), "gitlab.com".to_string()));
+        assert_eq!(clone_url("ssh://github.com/org/repo").unwrap().0, "ssh://github.com-work/org/repo");
+    }
+
+    #[test]
+    fn clone_url_rewrites_https() {
+        for url in ["https://github.com/org/repo", "https://github.com/org/repo/", "https://github.com/org/repo.git", "http://user@github.com:443/org/repo"] {
+            assert_eq!(clone_url(url).unwrap().0, "git@github.com-work:org/repo.git", "{}", url);
+        }
+        for url in ["https://github.com/org/repo/tree/main", "https://github.com/org/repo/blob/main/src/lib.rs", "https://github.com/org/repo?tab=readme#top"] {
+            assert_eq!(clone_url(url).unwrap().0, "git@github.com-work:org/repo.git", "{}", url);
+        }
+        assert_eq!(clone_url("https://gitlab.com/group/sub/repo/-/tree/main").unwrap().0, "git@gitlab.com-work:group/sub/repo.git");
+        assert!(clone_url("https://github.com/org").is_err());
+        assert!(clone_url("https://github.com/").is_err());
+    }
+
+    #[test]
+    fn clone_url_expands_owner_repo() {
+        assert_eq!(clone_url("org/repo").unwrap(), ("git@github.com-work:org/repo.git".to_string(), "github.com".to_string()));
+        assert_eq!(clone_url("org/repo.git").unwrap().0, "git@github.com-work:org/repo.git");
+        assert!(clone_url("org/repo/extra").is_err());
+        assert!(clone_url("/repo").is_err());
+        assert!(clone_url("repo").is_err());
+    }
+
+    #[test]
+    fn clone_url_rejects_unbound_hosts() {
+        assert!(clone_url("git@bitbucket.org:org/repo.git").is_err());
+        assert!(clone_url("https://codeberg.org/org/repo").is_err());
+        assert!(clone_url("ftp://github.com/org/repo").is_err());
+    }
+
+    #[test]
+    fn clone_dir_names() {
+        assert_eq!(clone_dir_name("git@github.com-work:org/repo.git").as_deref(), Some("repo"));
+        assert_eq!(clone_dir_name("ssh://github.com-work/org/repo/").as_deref(), Some("repo"));
+        assert_eq!(clone_dir_name("git@github.com-work:repo").as_deref(), Some("repo"));
+        assert_eq!(clone_dir_name(".git"), None);
     }
 }
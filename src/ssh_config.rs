@@ -0,0 +1,692 @@
+//! A lossless model of an OpenSSH client config: every line is kept verbatim, so parsing and
+//! serializing an untouched file gives back the same bytes, and edits only rewrite the lines they change.
+
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const INDENT: &str = "  ";
+const BEGIN_MARKER: &str = "# BEGIN ghp managed";
+const END_MARKER: &str = "# END ghp managed";
+
+#[derive(Debug, Clone)]
+struct Line {
+    /// The raw text, including its line ending.
+    text: String,
+    directive: Option<Directive>,
+}
+
+#[derive(Debug, Clone)]
+struct Directive {
+    keyword: String,
+    args: Vec<String>,
+    /// Byte range of the arguments within the line, excluding trailing whitespace.
+    value: Range<usize>,
+}
+
+impl Line {
+    fn parse(text: String) -> Self {
+        let directive = Directive::parse(&text);
+        Self { text, directive }
+    }
+
+    fn is_blank(&self) -> bool {
+        self.text.trim().is_empty()
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.directive.as_ref().is_some_and(|directive| directive.keyword.eq_ignore_ascii_case(keyword))
+    }
+
+    fn indent(&self) -> &str {
+        let content = self.text.trim_start_matches([' ', '\t']);
+        &self.text[..self.text.len() - content.len()]
+    }
+}
+
+impl Directive {
+    /// Splits a line into keyword and arguments, accepting both `Key value` and `Key=value`.
+    fn parse(text: &str) -> Option<Self> {
+        let body = text.trim_end_matches(['\n', '\r']);
+        let start = body.len() - body.trim_start_matches([' ', '\t']).len();
+        let rest = &body[start..];
+        if rest.is_empty() || rest.starts_with('#') {
+            return None;
+        }
+
+        let keyword_len = rest.find([' ', '\t', '=']).unwrap_or(rest.len());
+        let keyword = rest[..keyword_len].to_string();
+        let mut value_start = start + keyword_len;
+        value_start += body[value_start..].len() - body[value_start..].trim_start_matches([' ', '\t']).len();
+        if body[value_start..].starts_with('=') {
+            value_start += 1;
+            value_start += body[value_start..].len() - body[value_start..].trim_start_matches([' ', '\t']).len();
+        }
+        let value_end = body.trim_end_matches([' ', '\t']).len().max(value_start);
+
+        Some(Self {
+            keyword,
+            args: split_args(&body[value_start..value_end]),
+            value: value_start..value_end,
+        })
+    }
+}
+
+/// Splits arguments on whitespace, keeping quoted strings together.
+fn split_args(value: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    for c in value.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, ' ' | '\t') => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+fn quote_arg(value: &str) -> String {
+    if value.is_empty() || value.contains([' ', '\t']) {
+        format!("\"{}\"", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// A directive in a block, with its arguments exactly as written.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub keyword: String,
+    pub value: String,
+    /// 1-based line number.
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Section {
+    /// Directives before the first `Host` or `Match` line, which apply to every host.
+    Global,
+    Host(Vec<String>),
+    Match(Vec<String>),
+}
+
+/// A run of lines starting at a `Host` or `Match` line (or the start of the file) and
+/// ending before the next one.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub section: Section,
+    lines: Range<usize>,
+}
+
+impl Block {
+    /// 1-based line number of the block's first line.
+    pub fn line(&self) -> usize {
+        self.lines.start + 1
+    }
+
+    /// Whether this is a `Host` block naming `host` literally (not through a wildcard or negation).
+    pub fn names(&self, host: &str) -> bool {
+        match &self.section {
+            Section::Host(patterns) => patterns.iter().any(|pattern| pattern.eq_ignore_ascii_case(host)),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SshConfig {
+    lines: Vec<Line>,
+}
+
+impl SshConfig {
+    pub fn parse(content: &str) -> Self {
+        Self {
+            lines: content.split_inclusive('\n').map(|text| Line::parse(text.to_string())).collect(),
+        }
+    }
+
+    pub fn blocks(&self) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        let mut current = Block { section: Section::Global, lines: 0..0 };
+        for (index, line) in self.lines.iter().enumerate() {
+            let section = match &line.directive {
+                Some(directive) if directive.keyword.eq_ignore_ascii_case("Host") => {
+                    Some(Section::Host(directive.args.clone()))
+                }
+                Some(directive) if directive.keyword.eq_ignore_ascii_case("Match") => {
+                    Some(Section::Match(directive.args.clone()))
+                }
+                _ => None,
+            };
+            if let Some(section) = section {
+                current.lines.end = index;
+                blocks.push(current);
+                current = Block { section, lines: index..index };
+            }
+        }
+        current.lines.end = self.lines.len();
+        blocks.push(current);
+        blocks
+    }
+
+    /// The first `Host` block that names `host` literally.
+    pub fn find_host(&self, host: &str) -> Option<Block> {
+        self.blocks().into_iter().find(|block| block.names(host))
+    }
+
+    /// A copy of the lines between the `# BEGIN ghp managed` and `# END ghp managed` markers.
+    pub fn managed(&self) -> SshConfig {
+        let lines = match self.managed_range() {
+            Some(range) => self.lines[range].to_vec(),
+            None => Vec::new(),
+        };
+        SshConfig { lines }
+    }
+
+    /// Replaces the managed region with `managed`. If there is no region yet, one is inserted after
+    /// the global directives but ahead of the first global `Include` and every `Host` and `Match` block,
+    /// so that its entries take precedence over both. The region is then closed with `Match all`, so
+    /// that the `Include` and the global directives after it still apply to every host rather than
+    /// only to the region's last `Host` block.
+    pub fn set_managed(&mut self, mut managed: SshConfig) {
+        if let Some(last) = managed.lines.len().checked_sub(1) {
+            managed.ensure_newline(last);
+        }
+        if let Some(range) = self.managed_range() {
+            self.lines.splice(range, managed.lines);
+            return;
+        }
+        if managed.lines.is_empty() {
+            return;
+        }
+
+        let global = &self.blocks()[0];
+        let include = self.body(global).find(|&index| self.lines[index].is_keyword("Include"));
+        let at = match include {
+            // Keep the comments directly above the `Include` with it.
+            Some(mut at) => {
+                while at > 0 && self.lines[at - 1].directive.is_none() && !self.lines[at - 1].is_blank() {
+                    at -= 1;
+                }
+                at
+            }
+            None => self.body(global).last().map_or(0, |index| index + 1),
+        };
+        let mut region = Vec::new();
+        if at > 0 {
+            self.ensure_newline(at - 1);
+            if !self.lines[at - 1].is_blank() {
+                region.push(Line::parse("\n".to_string()));
+            }
+        }
+        region.push(Line::parse(format!("{}\n", BEGIN_MARKER)));
+        region.extend(managed.lines);
+        region.push(Line::parse(format!("{}\n", END_MARKER)));
+        if include.is_some() {
+            region.push(Line::parse("Match all\n".to_string()));
+        }
+        if self.lines.get(at).is_some_and(|line| !line.is_blank()) {
+            region.push(Line::parse("\n".to_string()));
+        }
+        self.lines.splice(at..at, region);
+    }
+
+    /// Whether `block` lies inside the managed region.
+    pub fn is_managed(&self, block: &Block) -> bool {
+        self.managed_range().is_some_and(|range| range.contains(&block.lines.start))
+    }
+
+    fn managed_range(&self) -> Option<Range<usize>> {
+        let begin = self.lines.iter().position(|line| line.text.trim() == BEGIN_MARKER)?;
+        let end = self.lines[begin + 1..].iter().position(|line| line.text.trim() == END_MARKER)?;
+        Some(begin + 1..begin + 1 + end)
+    }
+
+    /// The arguments of the first `keyword` directive in `block`, joined by spaces.
+    pub fn get(&self, block: &Block, keyword: &str) -> Option<String> {
+        self.body(block)
+            .find_map(|index| match &self.lines[index].directive {
+                Some(directive) if directive.keyword.eq_ignore_ascii_case(keyword) => Some(directive.args.join(" ")),
+                _ => None,
+            })
+    }
+
+    /// Every directive in `block`, in order.
+    pub fn entries(&self, block: &Block) -> Vec<Entry> {
+        self.body(block)
+            .filter_map(|index| {
+                let line = &self.lines[index];
+                let directive = line.directive.as_ref()?;
+                Some(Entry {
+                    keyword: directive.keyword.clone(),
+                    value: line.text[directive.value.clone()].to_string(),
+                    line: index + 1,
+                })
+            })
+            .collect()
+    }
+
+    /// The arguments of every `Include` directive in `block`.
+    pub fn includes(&self, block: &Block) -> Vec<String> {
+        self.body(block)
+            .filter_map(|index| match &self.lines[index].directive {
+                Some(directive) if directive.keyword.eq_ignore_ascii_case("Include") => Some(directive.args.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// Sets `keyword` to the single argument `value` in `block`, quoting it if needed.
+    pub fn set(&mut self, block: &Block, keyword: &str, value: &str) {
+        self.set_raw(block, keyword, &quote_arg(value));
+    }
+
+    /// Sets `keyword` to `value`, written as is: the first existing directive is rewritten in place and
+    /// any repeats are dropped, otherwise a new line is added after the block's last directive.
+    pub fn set_raw(&mut self, block: &Block, keyword: &str, value: &str) {
+        let matching: Vec<usize> = self.body(block)
+            .filter(|&index| self.lines[index].is_keyword(keyword))
+            .collect();
+
+        match matching.split_first() {
+            Some((&first, rest)) => {
+                self.replace_value(first, value);
+                for &index in rest.iter().rev() {
+                    self.lines.remove(index);
+                }
+            }
+            None => {
+                let last = self.last_directive(block);
+                let indent = match self.body(block).last() {
+                    Some(index) => self.lines[index].indent().to_string(),
+                    None => INDENT.to_string(),
+                };
+                self.ensure_newline(last);
+                self.lines.insert(last + 1, Line::parse(format!("{}{} {}\n", indent, keyword, value)));
+            }
+        }
+    }
+
+    /// Removes `block` along with the blank lines and indented comments that follow it; comments
+    /// starting at the beginning of a line are kept, since they usually introduce the next block.
+    fn remove(&mut self, block: &Block) {
+        let body_end = self.last_directive(block) + 1;
+        let end = (body_end..block.lines.end)
+            .find(|&index| {
+                let line = &self.lines[index];
+                !line.is_blank() && line.indent().is_empty()
+            })
+            .unwrap_or(block.lines.end);
+        let was_last = block.lines.end == self.lines.len();
+        self.lines.drain(block.lines.start..end);
+
+        if was_last {
+            while self.lines.last().is_some_and(Line::is_blank) {
+                self.lines.pop();
+            }
+        }
+    }
+
+    /// Removes every `keyword` directive from `block`.
+    pub fn unset(&mut self, block: &Block, keyword: &str) {
+        let matching: Vec<usize> = self.body(block)
+            .filter(|&index| self.lines[index].is_keyword(keyword))
+            .collect();
+        for index in matching.into_iter().rev() {
+            self.lines.remove(index);
+        }
+    }
+
+    /// Stops `host` from matching its block: the pattern is dropped from the `Host` line, or the
+    /// whole block is removed if it was the only one. Returns whether anything changed.
+    pub fn remove_host(&mut self, host: &str) -> bool {
+        let Some(block) = self.find_host(host) else {
+            return false;
+        };
+        self.remove_patterns(&block, |pattern| pattern.eq_ignore_ascii_case(host));
+        true
+    }
+
+    /// Drops the patterns matching `remove` from a `Host` block, removing the block if none are left.
+    pub fn remove_patterns(&mut self, block: &Block, remove: impl Fn(&str) -> bool) {
+        let Section::Host(patterns) = &block.section else {
+            return;
+        };
+        let remaining: Vec<String> = patterns.iter()
+            .filter(|pattern| !remove(pattern))
+            .map(|pattern| quote_arg(pattern))
+            .collect();
+        if remaining.is_empty() {
+            self.remove(block);
+        } else if remaining.len() != patterns.len() {
+            self.replace_value(block.lines.start, &remaining.join(" "));
+        }
+    }
+
+    /// Appends a copy of `block` from `other` as `Host host`, keeping its directives and comments verbatim.
+    pub fn append_copy(&mut self, other: &SshConfig, block: &Block, host: &str) {
+        self.push_separator();
+        self.lines.push(Line::parse(format!("Host {}\n", host)));
+        let end = other.last_directive(block) + 1;
+        for line in &other.lines[block.lines.start + 1..end] {
+            self.lines.push(line.clone());
+        }
+        if let Some(last) = self.lines.len().checked_sub(1) {
+            self.ensure_newline(last);
+        }
+    }
+
+    /// Appends a new `Host` block, separated from the previous content by a blank line.
+    pub fn append_host(&mut self, host: &str, directives: &[(&str, &str)]) {
+        self.push_separator();
+        self.lines.push(Line::parse(format!("Host {}\n", host)));
+        for (keyword, value) in directives {
+            self.lines.push(Line::parse(format!("{}{} {}\n", INDENT, keyword, quote_arg(value))));
+        }
+    }
+
+    /// Ends the last line and adds a blank line after it, unless the config is empty.
+    fn push_separator(&mut self) {
+        if let Some(last) = self.lines.len().checked_sub(1) {
+            self.ensure_newline(last);
+            if !self.lines[last].is_blank() {
+                self.lines.push(Line::parse("\n".to_string()));
+            }
+        }
+    }
+
+    /// Rewrites the arguments of the directive on line `index`, keeping its keyword and spacing.
+    fn replace_value(&mut self, index: usize, value: &str) {
+        let line = &self.lines[index];
+        let range = line.directive.as_ref().map(|directive| directive.value.clone()).unwrap_or_default();
+        let text = format!("{}{}{}", &line.text[..range.start], value, &line.text[range.end..]);
+        self.lines[index] = Line::parse(text);
+    }
+
+    /// Indices of the directive lines in `block`, excluding the `Host`/`Match` line itself.
+    fn body(&self, block: &Block) -> impl Iterator<Item = usize> + '_ {
+        let start = match block.section {
+            Section::Global => block.lines.start,
+            _ => block.lines.start + 1,
+        };
+        (start..block.lines.end).filter(|&index| self.lines[index].directive.is_some())
+    }
+
+    /// Index of the last directive in `block`, or its header if it has none.
+    fn last_directive(&self, block: &Block) -> usize {
+        self.body(block).last().unwrap_or(block.lines.start)
+    }
+
+    fn ensure_newline(&mut self, index: usize) {
+        if let Some(line) = self.lines.get_mut(index) {
+            if !line.text.ends_with('\n') {
+                line.text.push('\n');
+            }
+        }
+    }
+}
+
+impl fmt::Display for SshConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            f.write_str(&line.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a `Host` line with `patterns` applies to `host`: some pattern matches it and no negated
+/// (`!`) pattern does.
+pub fn matches_host(patterns: &[String], host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard_match(&negated.to_ascii_lowercase(), &host) => return false,
+            Some(_) => {}
+            None => matched |= wildcard_match(&pattern.to_ascii_lowercase(), &host),
+        }
+    }
+    matched
+}
+
+pub fn is_wildcard(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Matches `text` against a pattern where `*` stands for any run of characters and `?` for one.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The files an `Include` argument refers to, sorted as SSH reads them. Relative paths are taken from
+/// `ssh_dir`, and `*`/`?` are expanded in every path component.
+pub fn include_paths(pattern: &Path, ssh_dir: &Path) -> Vec<PathBuf> {
+    let pattern = ssh_dir.join(pattern);
+    let mut candidates = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let component = component.as_os_str();
+        let name = component.to_string_lossy();
+        if !is_wildcard(&name) {
+            for candidate in &mut candidates {
+                candidate.push(component);
+            }
+            continue;
+        }
+        let mut expanded = Vec::new();
+        for candidate in &candidates {
+            let Ok(entries) = fs::read_dir(candidate) else {
+                continue;
+            };
+            let mut matches: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|entry| (!entry.starts_with('.') || name.starts_with('.')) && wildcard_match(&name, entry))
+                .map(|entry| candidate.join(entry))
+                .collect();
+            matches.sort();
+            expanded.extend(matches);
+        }
+        candidates = expanded;
+    }
+    candidates.retain(|path| path.is_file());
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &str) {
+        assert_eq!(SshConfig::parse(input).to_string(), input);
+    }
+
+    #[test]
+    fn round_trips_untouched_content() {
+        round_trip("Host github.com gist.github.com\n  HostName github.com\n  IdentityFile ~/.ssh/id_a\n");
+        round_trip("Host=github.com\n  User=git\n");
+        round_trip("Host github.com\n\tHostName github.com\n\tIdentityFile \"~/.ssh/my key\"\n");
+        round_trip("Match host github.com exec \"true\"\n  User git\n");
+        round_trip("Include config.d/*\nInclude ~/.ssh/other\n\nHost *\n  ServerAliveInterval 60\n");
+        round_trip("# my hosts\nHost github.com # trailing\n  # indented comment\n  User git\n\n# next\n");
+        round_trip("Host github.com\n  User git");
+        round_trip("");
+    }
+
+    #[test]
+    fn parses_sections() {
+        let config = SshConfig::parse("Include a\nHost=github.com gist.github.com\n\tUser git\nMatch all\n  User x\n");
+        let blocks = config.blocks();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].section, Section::Global);
+        assert_eq!(blocks[1].section, Section::Host(vec!["github.com".to_string(), "gist.github.com".to_string()]));
+        assert_eq!(blocks[2].section, Section::Match(vec!["all".to_string()]));
+        assert_eq!(config.includes(&blocks[0]), vec!["a".to_string()]);
+        assert_eq!(config.get(&blocks[1], "user").as_deref(), Some("git"));
+        assert_eq!(config.find_host("gist.github.com").map(|block| block.line()), Some(2));
+    }
+
+    #[test]
+    fn set_edits_in_place() {
+        let mut config = SshConfig::parse("# keep\nHost github.com\n\tUser=git\n\tIdentityFile a\n\tIdentityFile b\n\nHost other\n  User x\n");
+        let block = config.find_host("github.com").unwrap();
+        config.set(&block, "IdentityFile", "/keys/my key");
+        let block = config.find_host("github.com").unwrap();
+        config.set(&block, "Port", "443");
+        assert_eq!(
+            config.to_string(),
+            "# keep\nHost github.com\n\tUser=git\n\tIdentityFile \"/keys/my key\"\n\tPort 443\n\nHost other\n  User x\n"
+        );
+    }
+
+    #[test]
+    fn set_without_trailing_newline() {
+        let mut config = SshConfig::parse("Host github.com\n  User git");
+        let block = config.find_host("github.com").unwrap();
+        config.set(&block, "Port", "22");
+        assert_eq!(config.to_string(), "Host github.com\n  User git\n  Port 22\n");
+    }
+
+    #[test]
+    fn remove_host_drops_block_or_pattern() {
+        let mut config = SshConfig::parse("Host a b\n  User x\n\nHost c\n  User y\n  # about c\n\n# next\nHost d\n  User z\n");
+        assert!(config.remove_host("b"));
+        assert!(config.remove_host("c"));
+        assert!(!config.remove_host("missing"));
+        assert_eq!(config.to_string(), "Host a\n  User x\n\n# next\nHost d\n  User z\n");
+
+        assert!(config.remove_host("d"));
+        assert_eq!(config.to_string(), "Host a\n  User x\n\n# next\n");
+    }
+
+    #[test]
+    fn remove_patterns_keeps_other_names() {
+        let mut config = SshConfig::parse("Host github.com github.com-work other\n  User git\n");
+        let block = config.find_host("other").unwrap();
+        config.remove_patterns(&block, |pattern| pattern.starts_with("github.com"));
+        assert_eq!(config.to_string(), "Host other\n  User git\n");
+
+        let block = config.find_host("other").unwrap();
+        config.remove_patterns(&block, |_| true);
+        assert_eq!(config.to_string(), "");
+    }
+
+    #[test]
+    fn set_managed_inserts_after_global_directives() {
+        let mut config = SshConfig::parse("# mine\nServerAliveInterval 30\n\nHost *\n  User me\n");
+        let mut managed = SshConfig::default();
+        managed.append_host("github.com", &[("IdentityFile", "/k")]);
+        config.set_managed(managed);
+        assert_eq!(
+            config.to_string(),
+            "# mine\nServerAliveInterval 30\n\n# BEGIN ghp managed\nHost github.com\n  IdentityFile /k\n# END ghp managed\n\nHost *\n  User me\n"
+        );
+        assert!(config.is_managed(&config.find_host("github.com").unwrap()));
+        assert!(!config.is_managed(&config.find_host("*").unwrap()));
+
+        let mut managed = config.managed();
+        managed.remove_host("github.com");
+        config.set_managed(managed);
+        assert_eq!(config.to_string(), "# mine\nServerAliveInterval 30\n\n# BEGIN ghp managed\n# END ghp managed\n\nHost *\n  User me\n");
+    }
+
+    #[test]
+    fn set_managed_inserts_before_global_includes() {
+        let mut managed = SshConfig::default();
+        managed.append_host("github.com", &[("IdentityFile", "/k")]);
+        let region = "# BEGIN ghp managed\nHost github.com\n  IdentityFile /k\n# END ghp managed\nMatch all\n";
+
+        let mut config = SshConfig::parse("AddKeysToAgent yes\n# local hosts\nInclude config.d/*\nForwardAgent no\n\nHost *\n  User me\n");
+        config.set_managed(managed.clone());
+        assert_eq!(
+            config.to_string(),
+            format!("AddKeysToAgent yes\n\n{}\n# local hosts\nInclude config.d/*\nForwardAgent no\n\nHost *\n  User me\n", region)
+        );
+        // The `Include` and the directives after it must still apply to every host.
+        let blocks = config.blocks();
+        let include = blocks.iter().find(|block| !config.includes(block).is_empty()).unwrap();
+        assert_eq!(include.section, Section::Match(vec!["all".to_string()]));
+        assert_eq!(config.get(include, "ForwardAgent").as_deref(), Some("no"));
+        assert!(!config.is_managed(include));
+
+        // Replacing the region later leaves the scope reset alone.
+        let mut managed = config.managed();
+        managed.remove_host("github.com");
+        config.set_managed(managed);
+        assert_eq!(
+            config.to_string(),
+            "AddKeysToAgent yes\n\n# BEGIN ghp managed\n# END ghp managed\nMatch all\n\n# local hosts\nInclude config.d/*\nForwardAgent no\n\nHost *\n  User me\n"
+        );
+
+        let mut managed = SshConfig::default();
+        managed.append_host("github.com", &[("IdentityFile", "/k")]);
+        let mut config = SshConfig::parse("Include config.d/*\n");
+        config.set_managed(managed);
+        assert_eq!(config.to_string(), format!("{}\nInclude config.d/*\n", region));
+    }
+
+    #[test]
+    fn set_managed_replaces_existing_region_only() {
+        let input = "Host a\n  User a\n# BEGIN ghp managed\nHost old\n  User old\n# END ghp managed\nHost b\n  User b\n";
+        let mut config = SshConfig::parse(input);
+        let mut managed = SshConfig::default();
+        managed.append_host("new", &[("User", "new")]);
+        config.set_managed(managed);
+        assert_eq!(
+            config.to_string(),
+            "Host a\n  User a\n# BEGIN ghp managed\nHost new\n  User new\n# END ghp managed\nHost b\n  User b\n"
+        );
+    }
+
+    #[test]
+    fn set_managed_leaves_empty_config_alone() {
+        let mut config = SshConfig::parse("");
+        config.set_managed(SshConfig::default());
+        assert_eq!(config.to_string(), "");
+    }
+}
@@ -0,0 +1,731 @@
+//! A small TOML reader and writer covering the subset used by `~/.ghp`:
+//! tables, arrays of tables, strings, integers, booleans, arrays and inline tables.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+
+    fn is_array_of_tables(&self) -> bool {
+        match self {
+            Value::Array(items) => !items.is_empty() && items.iter().all(|item| matches!(item, Value::Table(_))),
+            _ => false,
+        }
+    }
+}
+
+/// A key/value pair together with the line it was defined on (0 if built in memory).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    entries: Vec<Entry>,
+    /// Whether the table was created by a `[header]` or `key = ...`, rather than implicitly.
+    defined: bool,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    pub fn entry(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.key == key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entry(key).map(|entry| &entry.value)
+    }
+
+    pub fn insert(&mut self, key: &str, value: Value) {
+        match self.entries.iter_mut().find(|entry| entry.key == key) {
+            Some(entry) => entry.value = value,
+            None => self.entries.push(Entry { key: key.to_string(), value, line: 0 }),
+        }
+    }
+
+    fn entry_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|entry| entry.key == key)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// How deeply arrays and inline tables may nest, so hostile input cannot overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+pub fn parse(input: &str) -> Result<Table, ParseError> {
+    let mut parser = Parser { chars: input.chars().collect(), pos: 0, line: 1, depth: 0 };
+    let mut root = Table::new();
+    let mut current: Vec<String> = Vec::new();
+
+    loop {
+        parser.skip_trivia();
+        let Some(c) = parser.peek() else { break };
+        let line = parser.line;
+
+        if c == '[' {
+            parser.pos += 1;
+            let is_array = parser.peek() == Some('[');
+            if is_array {
+                parser.pos += 1;
+            }
+            parser.skip_spaces();
+            let path = parser.parse_key_path()?;
+            parser.skip_spaces();
+            parser.expect(']')?;
+            if is_array {
+                parser.expect(']')?;
+            }
+            parser.end_of_line()?;
+
+            if is_array {
+                push_array_table(&mut root, &path, line).map_err(|message| ParseError { line, message })?;
+            } else {
+                define_table(&mut root, &path, line).map_err(|message| ParseError { line, message })?;
+            }
+            current = path;
+        } else {
+            let path = parser.parse_key_path()?;
+            parser.skip_spaces();
+            parser.expect('=')?;
+            parser.skip_spaces();
+            let value = parser.parse_value()?;
+            parser.end_of_line()?;
+
+            let table = table_at(&mut root, &current).map_err(|message| ParseError { line, message })?;
+            insert_dotted(table, &path, value, line).map_err(|message| ParseError { line, message })?;
+        }
+    }
+
+    Ok(root)
+}
+
+/// Walks `path` from `root`, descending into the last element of arrays of tables.
+fn table_at<'a>(root: &'a mut Table, path: &[String]) -> Result<&'a mut Table, String> {
+    let mut table = root;
+    for key in path {
+        let entry = table.entry_mut(key)
+            .ok_or_else(|| format!("table '{}' is not defined", key))?;
+        table = match &mut entry.value {
+            Value::Table(inner) => inner,
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(inner)) => inner,
+                _ => return Err(format!("'{}' is not a table", key)),
+            },
+            _ => return Err(format!("'{}' is not a table", key)),
+        };
+    }
+    Ok(table)
+}
+
+/// Like `table_at`, but creates missing intermediate tables, marking them as `defined`
+/// when they come from a dotted key rather than a `[header]`.
+fn ensure_table<'a>(root: &'a mut Table, path: &[String], line: usize, defined: bool) -> Result<&'a mut Table, String> {
+    let mut table = root;
+    for key in path {
+        if table.entry(key).is_none() {
+            table.entries.push(Entry { key: key.clone(), value: Value::Table(Table { entries: Vec::new(), defined }), line });
+        }
+        let entry = table.entry_mut(key).expect("entry was just inserted");
+        table = match &mut entry.value {
+            Value::Table(inner) => inner,
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(inner)) => inner,
+                _ => return Err(format!("'{}' is not a table", key)),
+            },
+            _ => return Err(format!("'{}' is not a table", key)),
+        };
+    }
+    Ok(table)
+}
+
+fn define_table(root: &mut Table, path: &[String], line: usize) -> Result<(), String> {
+    let (last, parents) = path.split_last().expect("key paths are never empty");
+    let parent = ensure_table(root, parents, line, false)?;
+    match parent.entry_mut(last) {
+        Some(Entry { value: Value::Table(table), .. }) if !table.defined => {
+            table.defined = true;
+            Ok(())
+        }
+        Some(_) => Err(format!("'{}' is defined more than once", path.join("."))),
+        None => {
+            parent.entries.push(Entry {
+                key: last.clone(),
+                value: Value::Table(Table { entries: Vec::new(), defined: true }),
+                line,
+            });
+            Ok(())
+        }
+    }
+}
+
+fn push_array_table(root: &mut Table, path: &[String], line: usize) -> Result<(), String> {
+    let (last, parents) = path.split_last().expect("key paths are never empty");
+    let parent = ensure_table(root, parents, line, false)?;
+    let table = Value::Table(Table { entries: Vec::new(), defined: true });
+    match parent.entry_mut(last) {
+        Some(Entry { value: Value::Array(items), .. }) if items.iter().all(|item| matches!(item, Value::Table(_))) => {
+            items.push(table);
+            Ok(())
+        }
+        Some(_) => Err(format!("'{}' is not an array of tables", path.join("."))),
+        None => {
+            parent.entries.push(Entry { key: last.clone(), value: Value::Array(vec![table]), line });
+            Ok(())
+        }
+    }
+}
+
+fn insert_dotted(table: &mut Table, path: &[String], value: Value, line: usize) -> Result<(), String> {
+    let (last, parents) = path.split_last().expect("key paths are never empty");
+    let table = ensure_table(table, parents, line, true)?;
+    if table.entry(last).is_some() {
+        return Err(format!("key '{}' is defined more than once", path.join(".")));
+    }
+    let value = match value {
+        Value::Table(mut inner) => {
+            inner.defined = true;
+            Value::Table(inner)
+        }
+        other => other,
+    };
+    table.entries.push(Entry { key: last.clone(), value, line });
+    Ok(())
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError { line: self.line, message: message.into() }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some('\n' | '\r') => Err(self.error(format!("expected '{}', found end of line", expected))),
+            Some(c) => Err(self.error(format!("expected '{}', found '{}'", expected, c))),
+            None => Err(self.error(format!("expected '{}', found end of file", expected))),
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), Some('\n') | None) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Skips whitespace, newlines and comments.
+    fn skip_trivia(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') => {
+                    self.next();
+                }
+                Some('\r') if self.chars.get(self.pos + 1) == Some(&'\n') => {
+                    self.pos += 1;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_spaces();
+        self.skip_comment();
+        if self.peek() == Some('\r') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.next();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("unexpected '{}' after value", c))),
+        }
+    }
+
+    fn parse_key_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut path = vec![self.parse_key()?];
+        loop {
+            self.skip_spaces();
+            if self.peek() != Some('.') {
+                return Ok(path);
+            }
+            self.pos += 1;
+            self.skip_spaces();
+            path.push(self.parse_key()?);
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string(),
+            Some('\'') => self.parse_literal_string(),
+            _ => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if is_bare_key_char(c)) {
+                    self.pos += 1;
+                }
+                if start == self.pos {
+                    return Err(match self.peek() {
+                        Some(c) => self.error(format!("expected a key, found '{}'", c)),
+                        None => self.error("expected a key, found end of file"),
+                    });
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => {
+                if self.chars[self.pos..].starts_with(&['"', '"', '"']) {
+                    return Err(self.error("multi-line strings are not supported"));
+                }
+                self.parse_basic_string().map(Value::String)
+            }
+            Some('\'') => self.parse_literal_string().map(Value::String),
+            Some(c @ ('[' | '{')) => {
+                if self.depth == MAX_DEPTH {
+                    return Err(self.error(format!("nesting deeper than {} levels", MAX_DEPTH)));
+                }
+                self.depth += 1;
+                let value = if c == '[' { self.parse_array() } else { self.parse_inline_table() };
+                self.depth -= 1;
+                value
+            }
+            Some('t' | 'f') => {
+                let word = self.take_word();
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => Err(self.error(format!("invalid value '{}' (strings must be quoted)", word))),
+                }
+            }
+            Some(c) if c == '+' || c == '-' || c.is_ascii_digit() => {
+                let word = self.take_word();
+                word.replace('_', "")
+                    .parse::<i64>()
+                    .map(Value::Integer)
+                    .map_err(|_| self.error(format!("invalid integer '{}'", word)))
+            }
+            Some('\n') | None => Err(self.error("expected a value")),
+            Some(_) => {
+                let word = self.take_word();
+                Err(self.error(format!("invalid value '{}' (strings must be quoted)", word)))
+            }
+        }
+    }
+
+    fn take_word(&mut self) -> String {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if !c.is_whitespace() && !matches!(c, ',' | ']' | '}' | '#')) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.next() {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('u') => value.push(self.parse_unicode_escape(4)?),
+                    Some('U') => value.push(self.parse_unicode_escape(8)?),
+                    Some(c) => return Err(self.error(format!("invalid escape sequence '\\{}'", c))),
+                    None => return Err(self.error("unterminated string")),
+                },
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn parse_unicode_escape(&mut self, digits: usize) -> Result<char, ParseError> {
+        let mut code = 0;
+        for _ in 0..digits {
+            let digit = self.next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("invalid unicode escape"))?;
+            code = code * 16 + digit;
+        }
+        char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn parse_literal_string(&mut self) -> Result<String, ParseError> {
+        self.expect('\'')?;
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(value),
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_trivia();
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                return Ok(Value::Array(items));
+            }
+            items.push(self.parse_value()?);
+            self.skip_trivia();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn parse_inline_table(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut table = Table { entries: Vec::new(), defined: true };
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_spaces();
+            let line = self.line;
+            let path = self.parse_key_path()?;
+            self.skip_spaces();
+            self.expect('=')?;
+            self.skip_spaces();
+            let value = self.parse_value()?;
+            insert_dotted(&mut table, &path, value, line).map_err(|message| ParseError { line, message })?;
+            self.skip_spaces();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Value::Table(table));
+                }
+                _ => return Err(self.error("expected ',' or '}' in inline table")),
+            }
+        }
+    }
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn format_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(is_bare_key_char) {
+        key.to_string()
+    } else {
+        format_string(key)
+    }
+}
+
+fn format_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(value) => format_string(value),
+        Value::Integer(value) => value.to_string(),
+        Value::Boolean(value) => value.to_string(),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(format_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Table(table) => {
+            let entries: Vec<String> = table.entries.iter()
+                .map(|entry| format!("{} = {}", format_key(&entry.key), format_value(&entry.value)))
+                .collect();
+            if entries.is_empty() {
+                "{}".to_string()
+            } else {
+                format!("{{ {} }}", entries.join(", "))
+            }
+        }
+    }
+}
+
+/// Serializes `table` as a TOML document, using `[headers]` for nested tables.
+pub fn to_string(table: &Table) -> String {
+    let mut out = String::new();
+    write_table(&mut out, table, &[], false);
+    out
+}
+
+fn write_table(out: &mut String, table: &Table, path: &[String], is_array_element: bool) {
+    let (nested, simple): (Vec<&Entry>, Vec<&Entry>) = table.entries.iter()
+        .partition(|entry| matches!(entry.value, Value::Table(_)) || entry.value.is_array_of_tables());
+
+    if !path.is_empty() && (is_array_element || !simple.is_empty() || nested.is_empty()) {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let header: Vec<String> = path.iter().map(|key| format_key(key)).collect();
+        if is_array_element {
+            out.push_str(&format!("[[{}]]\n", header.join(".")));
+        } else {
+            out.push_str(&format!("[{}]\n", header.join(".")));
+        }
+    }
+    for entry in simple {
+        out.push_str(&format!("{} = {}\n", format_key(&entry.key), format_value(&entry.value)));
+    }
+
+    for entry in nested {
+        let mut child_path = path.to_vec();
+        child_path.push(entry.key.clone());
+        match &entry.value {
+            Value::Table(child) => write_table(out, child, &child_path, false),
+            Value::Array(items) => {
+                for item in items {
+                    if let Value::Table(child) = item {
+                        write_table(out, child, &child_path, true);
+                    }
+                }
+            }
+            _ => unreachable!("nested entries are tables or arrays of tables"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    fn string(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+
+    #[test]
+    fn parses_arrays_of_tables() {
+        let table = parse("[[a.b]]\nx = 1\n\n[[a.b]]\nx = 2\n[a.b.c]\ny = true\n").unwrap();
+        let Some(Value::Table(a)) = table.get("a") else { panic!("'a' is not a table") };
+        let Some(Value::Array(items)) = a.get("b") else { panic!("'a.b' is not an array") };
+        assert_eq!(items.len(), 2);
+        let Value::Table(first) = &items[0] else { panic!() };
+        let Value::Table(second) = &items[1] else { panic!() };
+        assert_eq!(first.get("x"), Some(&Value::Integer(1)));
+        assert_eq!(first.get("c"), None);
+        assert_eq!(second.get("x"), Some(&Value::Integer(2)));
+        let Some(Value::Table(c)) = second.get("c") else { panic!("'c' is not a table") };
+        assert_eq!(c.get("y"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn parses_dotted_keys() {
+        let table = parse("a.b = \"x\"\n\"quoted key\" . 'c' = 1\n[t]\nu.v = { w.x = 2 }\n").unwrap();
+        let Some(Value::Table(a)) = table.get("a") else { panic!() };
+        assert_eq!(a.get("b"), Some(&string("x")));
+        let Some(Value::Table(quoted)) = table.get("quoted key") else { panic!() };
+        assert_eq!(quoted.get("c"), Some(&Value::Integer(1)));
+        let Some(Value::Table(t)) = table.get("t") else { panic!() };
+        let Some(Value::Table(u)) = t.get("u") else { panic!() };
+        let Some(Value::Table(v)) = u.get("v") else { panic!() };
+        let Some(Value::Table(w)) = v.get("w") else { panic!() };
+        assert_eq!(w.get("x"), Some(&Value::Integer(2)));
+    }
+
+    #[test]
+    fn rejects_redefinitions() {
+        let error = |input: &str| parse(input).unwrap_err();
+        assert_eq!(error("a = 1\na = 2\n"), ParseError { line: 2, message: "key 'a' is defined more than once".to_string() });
+        assert_eq!(error("[t]\n[t]\n"), ParseError { line: 2, message: "'t' is defined more than once".to_string() });
+        assert_eq!(error("a.b = 1\n[a]\n").line, 2);
+        assert_eq!(error("t = { x = 1 }\n[t]\n").line, 2);
+        assert_eq!(error("[t]\nx = 1\n[t.x]\n").line, 3);
+        assert_eq!(error("x = { a = 1, a = 2 }\n").message, "key 'a' is defined more than once");
+        assert_eq!(error("[a]\n[[a]]\n").message, "'a' is not an array of tables");
+        assert!(parse("[a.b]\n[a]\n").is_ok());
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| format!("a = {}{}\n", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            parse(&nested(MAX_DEPTH + 1)).unwrap_err(),
+            ParseError { line: 1, message: "nesting deeper than 128 levels".to_string() }
+        );
+        assert!(parse(&format!("a = {}", "[".repeat(100_000))).is_err());
+        assert!(parse(&format!("a = {}", "{ b = ".repeat(100_000))).is_err());
+    }
+
+    #[test]
+    fn parses_escapes() {
+        let table = parse(r#"s = "q\" b\\ t\t n\n r\r \u00e9 \U0001F600"
+l = 'C:\path\n'
+"#).unwrap();
+        assert_eq!(table.get("s"), Some(&string("q\" b\\ t\t n\n r\r \u{e9} \u{1F600}")));
+        assert_eq!(table.get("l"), Some(&string("C:\\path\\n")));
+        assert_eq!(parse("s = \"\\x\"\n").unwrap_err().message, "invalid escape sequence '\\x'");
+        assert_eq!(parse("s = \"\\uD800\"\n").unwrap_err().message, "invalid unicode escape");
+        assert_eq!(parse("s = \"open\nt = 1\n").unwrap_err().message, "unterminated string");
+    }
+
+    #[test]
+    fn escapes_round_trip() {
+        let mut table = Table::new();
+        table.insert("s", string("q\" b\\ \t\n\r \u{1} é"));
+        table.insert("odd key", Value::Array(vec![string(""), Value::Integer(-3)]));
+        let text = to_string(&table);
+        assert_eq!(parse(&text).unwrap().get("s"), table.get("s"));
+        assert_eq!(parse(&text).unwrap().get("odd key"), table.get("odd key"));
+    }
+
+    #[test]
+    fn parses_crlf() {
+        let input = "# comment\r\nversion = 2\r\n\r\n[profiles.work] # trailing\r\nemail = \"a@b\"\r\nports = [\r\n  1,\r\n  2,\r\n]\r\n";
+        let table = parse(input).unwrap();
+        assert_eq!(table, parse(&input.replace("\r\n", "\n")).unwrap());
+        assert_eq!(table.entry("version").map(|entry| entry.line), Some(2));
+        let Some(Value::Table(profiles)) = table.get("profiles") else { panic!() };
+        let work = profiles.entry("work").unwrap();
+        assert_eq!(work.line, 4);
+        let Value::Table(work) = &work.value else { panic!() };
+        assert_eq!(work.entry("email").map(|entry| entry.line), Some(5));
+        assert_eq!(work.get("ports"), Some(&Value::Array(vec![Value::Integer(1), Value::Integer(2)])));
+        assert_eq!(parse("a = 1\r\nb = 2").unwrap().get("b"), Some(&Value::Integer(2)));
+    }
+
+    #[test]
+    fn saved_config_round_trips() {
+        let mut work = crate::Profile::new("Work Name".to_string(), "work@example.com".to_string(), PathBuf::from("~/.ssh/id work"));
+        work.host = "gitlab.example.com".to_string();
+        work.ssh_port = Some(2222);
+        work.ssh_user = Some("gitlab".to_string());
+        work.ssh_options = vec![("ProxyJump".to_string(), "bastion".to_string())];
+        work.signing_key = Some("~/.ssh/id_sign.pub".to_string());
+        work.signing_format = Some("ssh".to_string());
+        work.hosts = vec![
+            crate::HostBinding {
+                host: "github.com".to_string(),
+                ssh_key: PathBuf::from("~/.ssh/id_gh"),
+                ssh_port: None,
+                ssh_user: None,
+                ssh_options: Vec::new(),
+            },
+            crate::HostBinding {
+                host: "codeberg.org".to_string(),
+                ssh_key: PathBuf::from("~/.ssh/id_cb"),
+                ssh_port: Some(22),
+                ssh_user: Some("git".to_string()),
+                ssh_options: vec![("AddKeysToAgent".to_string(), "yes".to_string())],
+            },
+        ];
+        let personal = crate::Profile::new("Me \"Quoted\"".to_string(), "me@example.com".to_string(), PathBuf::from("~/.ssh/id_me"));
+        let config = crate::Config {
+            ssh_config_path: PathBuf::from("/home/me/.ssh/config"),
+            ghp_config_path: PathBuf::from("/home/me/.ghp"),
+            profiles: [("work".to_string(), work), ("personal".to_string(), personal)].into_iter().collect(),
+        };
+
+        let text = config.to_toml();
+        let table = parse(&text).unwrap();
+        assert_eq!(to_string(&table), text);
+        let reparsed = crate::Config::parse_config(&text, Path::new("/home/me/.ghp"), false).unwrap();
+        assert_eq!(reparsed.to_toml(), text);
+    }
+}